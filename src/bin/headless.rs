@@ -0,0 +1,179 @@
+// Runs the simulation without a browser and prints the car's trajectory.
+//
+// Usage:
+//   headless <level file> [--inputs <file>] [--ticks <n>] [--seed <n>] [--out <file>] [--save-replay <file>]
+//   headless --replay <file>
+//
+// The level is required, the random world has no ground to drive on.
+// www/resources/levels/ramps.ron is a good start.
+// The inputs file has one line per input change, applied from that tick on:
+//   # tick throttle brake steering [handbrake] [boost]
+//   0   1.0 0.0 0.0
+//   120 1.0 0.0 0.5 1
+//
+// --replay simulates a replay exported by the game and checks it ends in
+// the same state, which is how bug reports from players get reproduced.
+
+// With web the library logs and seeds through the browser, which panics here.
+#[cfg(feature = "web")]
+compile_error!("the headless runner needs --no-default-features --features native");
+
+use std::{env, fs, process};
+use std::io::{self, Write};
+
+use game_test::{GameContainer, GameInput};
+
+struct Options {
+    level: Option<String>,
+    inputs: Option<String>,
+    ticks: u64,
+    seed: u64,
+    out: Option<String>,
+    replay: Option<String>,
+    save_replay: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        level: None,
+        inputs: None,
+        ticks: 600,
+        seed: 0,
+        out: None,
+        replay: None,
+        save_replay: None,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        // Every flag takes a value.
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--inputs" => options.inputs = Some(value("--inputs")?),
+            "--out" => options.out = Some(value("--out")?),
+            "--replay" => options.replay = Some(value("--replay")?),
+            "--save-replay" => options.save_replay = Some(value("--save-replay")?),
+            "--ticks" => {
+                options.ticks = value("--ticks")?.parse().map_err(|_| "--ticks must be a number")?;
+            }
+            "--seed" => {
+                options.seed = value("--seed")?.parse().map_err(|_| "--seed must be a number")?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown flag {}", arg)),
+            _ => options.level = Some(arg),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_inputs(text: &str) -> Result<Vec<(u64, GameInput)>, String> {
+    // Lines of "tick throttle brake steering [handbrake] [boost]".
+    let mut inputs = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = || format!("Bad input on line {}: {}", line_number + 1, line);
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(error());
+        }
+        let number = |index: usize| -> Result<f32, String> {
+            fields.get(index).map_or(Ok(0.0), |field| field.parse().map_err(|_| error()))
+        };
+
+        let tick: u64 = fields[0].parse().map_err(|_| error())?;
+        let mut input = GameInput::new();
+        input.set_throttle(number(1)?);
+        input.set_brake(number(2)?);
+        input.set_steering(number(3)?);
+        input.set_handbrake(number(4)? != 0.0);
+        input.set_boost(number(5)? != 0.0);
+        inputs.push((tick, input));
+    }
+    // Apply them in order, whatever order the file had.
+    inputs.sort_by_key(|(tick, _)| *tick);
+    Ok(inputs)
+}
+
+fn check_replay(path: &str) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+
+    let mut game = GameContainer::create_with_seed(0);
+    game.try_play_replay(&bytes).map_err(|err| err.to_string())?;
+    println!("Replay matches after {} ticks, state {:016x}", game.tick(), game.state_hash());
+    Ok(())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    if let Some(path) = &options.replay {
+        return check_replay(path);
+    }
+
+    let path = options.level.as_ref().ok_or("Give a level file to drive on, or --replay <file>")?;
+    let source = fs::read_to_string(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+    let mut game = GameContainer::create_with_seed(options.seed);
+    game.try_load_level(&source).map_err(|err| err.to_string())?;
+
+    let inputs = match &options.inputs {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+            parse_inputs(&text)?
+        }
+        None => Vec::new(),
+    };
+
+    let mut out: Box<dyn Write> = match &options.out {
+        Some(path) => Box::new(fs::File::create(path).map_err(|err| format!("Can't create {}: {}", path, err))?),
+        None => Box::new(io::stdout()),
+    };
+    let write_error = |err: io::Error| err.to_string();
+
+    writeln!(out, "tick,x,y,z,rot_x,rot_y,rot_z,speed,rpm,gear").map_err(write_error)?;
+
+    let mut input = GameInput::new();
+    let mut next_input = 0;
+    for tick in 0..options.ticks {
+        // Switch to the latest input that started at or before this tick.
+        while next_input < inputs.len() && inputs[next_input].0 <= tick {
+            input = inputs[next_input].1;
+            next_input += 1;
+        }
+        game.step_with_input(&input);
+
+        // Find our car and write where it is.
+        let objects = game.log_entities();
+        let car = (0..objects.len() as usize)
+            .map(|index| objects.get(index))
+            .find(|object| object.name() == "car00");
+        if let Some(car) = car {
+            let (pos, rot) = (car.pos(), car.rot());
+            let state = game.car_state();
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                tick, pos[0], pos[1], pos[2], rot[0], rot[1], rot[2],
+                state.speed(), state.rpm(), state.gear(),
+            ).map_err(write_error)?;
+        }
+    }
+
+    if let Some(path) = &options.save_replay {
+        let bytes = game.export_replay();
+        if bytes.is_empty() {
+            return Err("Can't export the replay".to_string());
+        }
+        fs::write(path, bytes).map_err(|err| format!("Can't write {}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = parse_options().and_then(run);
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
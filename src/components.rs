@@ -1,22 +1,42 @@
+use nalgebra::{Point3, Vector3, Isometry3, DMatrix, point, vector};
 use rapier3d::prelude::{ColliderHandle, RigidBodyHandle};
+use serde::{Serialize, Deserialize};
 use specs::{Component, VecStorage, WorldExt, World};
+#[cfg(feature = "web")]
 use wasm_bindgen::prelude::wasm_bindgen;
 
+use crate::GameInput;
+
 pub fn register_components(world: &mut World) {
     world.register::<PlayerCar>();
     world.register::<PhysicsObject>();
+    world.register::<PreviousTransform>();
+    world.register::<WheelSet>();
+    world.register::<Engine>();
+    world.register::<Gearbox>();
+    world.register::<Tires>();
+    world.register::<Steering>();
+    world.register::<Trigger>();
+    world.register::<RaceProgress>();
+    world.register::<GhostCar>();
+    world.register::<ControlInput>();
+    world.register::<AiDriver>();
+    world.register::<PlayerSlot>();
+    world.register::<Recovery>();
+    world.register::<SurfaceMaterial>();
+    world.register::<TerrainMap>();
 
     world.register::<StaticObject>();
     world.register::<ModelName>();
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct StaticObject {
     pub colliders: Vec<ColliderHandle>
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct PhysicsObject {
     pub object_type: PhysicsType,
@@ -24,22 +44,657 @@ pub struct PhysicsObject {
     pub colliders: Vec<ColliderHandle>,
 }
 
-#[wasm_bindgen]
+// What the ground of an entity is made of, colliders without one are asphalt.
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+#[serde(rename_all = "snake_case")]
+pub enum SurfaceMaterial {
+    #[default]
+    Asphalt = 0,
+    Dirt = 1,
+    Grass = 2,
+    Ice = 3,
+    Mud = 4,
+    Sand = 5,
+}
+
+// How a surface treats the cars driving on it.
 #[derive(Clone, Copy, Debug)]
+pub struct SurfaceProperties {
+    // Multiplies how much force the tires can get out of their load.
+    pub grip: f32,
+    // Part of the load that pushes against the rolling wheels.
+    pub rolling_resistance: f32,
+    // Collider friction, for the chassis sliding over it.
+    pub friction: f32,
+}
+
+impl SurfaceMaterial {
+    pub fn properties(self) -> SurfaceProperties {
+        let (grip, rolling_resistance, friction) = match self {
+            SurfaceMaterial::Asphalt => (1.0, 0.015, 1.0),
+            SurfaceMaterial::Dirt => (0.7, 0.04, 0.8),
+            SurfaceMaterial::Grass => (0.6, 0.06, 0.7),
+            SurfaceMaterial::Ice => (0.15, 0.01, 0.05),
+            SurfaceMaterial::Mud => (0.45, 0.15, 0.6),
+            SurfaceMaterial::Sand => (0.55, 0.2, 0.7),
+        };
+        SurfaceProperties {
+            grip,
+            rolling_resistance,
+            friction,
+        }
+    }
+    pub fn from_index(index: u32) -> Option<SurfaceMaterial> {
+        // The numbers used by material grids, the same as `material as u32`.
+        match index {
+            0 => Some(SurfaceMaterial::Asphalt),
+            1 => Some(SurfaceMaterial::Dirt),
+            2 => Some(SurfaceMaterial::Grass),
+            3 => Some(SurfaceMaterial::Ice),
+            4 => Some(SurfaceMaterial::Mud),
+            5 => Some(SurfaceMaterial::Sand),
+            _ => None,
+        }
+    }
+}
+
+// The material of every point of a heightfield, so a road can
+// run through the grass. Laid out like the heights of the collider.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct TerrainMap {
+    // Rows go along z, columns along x, like rapier's heights.
+    pub materials: DMatrix<SurfaceMaterial>,
+    pub scale: Vector3<f32>,
+}
+
+impl TerrainMap {
+    pub fn material_at(&self, local_point: &Point3<f32>) -> SurfaceMaterial {
+        // The grid point closest to a point in the collider's space,
+        // the heightfield is centered on its rigidbody.
+        let (rows, columns) = self.materials.shape();
+        if rows == 0 || columns == 0 {
+            return SurfaceMaterial::default();
+        }
+        let index = |value: f32, size: f32, count: usize| {
+            let t = (value / size + 0.5).clamp(0.0, 1.0);
+            (t * (count - 1) as f32).round() as usize
+        };
+        let row = index(local_point.z, self.scale.z, rows);
+        let column = index(local_point.x, self.scale.x, columns);
+        self.materials[(row, column)]
+    }
+}
+
+// Where the rigidbody was before the last physics step,
+// used to interpolate between steps when rendering.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct PreviousTransform(pub Isometry3<f32>);
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum PhysicsType {
     Static,     // Never update position.
     Dynamic,    // Always update position.
 }
 
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct PlayerCar {
     pub touching_ground: bool,
 }
 
-#[derive(Component)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Axle {
+    Front,
+    Rear,
+}
+
+// A single raycast wheel. The first fields describe how the wheel
+// is mounted, the rest get updated by the SuspensionSystem every step.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Wheel {
+    // Where the spring is attached, relative to the car's center.
+    pub mount: Point3<f32>,
+    pub axle: Axle,
+    // If the engine pushes through this wheel.
+    pub driven: bool,
+    // If the steering wheel turns this wheel.
+    pub steered: bool,
+    // Length of the spring when nothing is pushing on it.
+    pub rest_length: f32,
+    pub radius: f32,
+    // Spring and damper constants.
+    pub stiffness: f32,
+    pub damping: f32,
+
+    // How much the spring is currently squished (0.0 to rest_length).
+    pub compression: f32,
+    pub in_contact: bool,
+    // In contact with something below the car, not a wall or the roof.
+    pub grounded: bool,
+    // Entity id of what the wheel stands on, and what that's made of.
+    pub surface: Option<u32>,
+    pub material: SurfaceMaterial,
+    pub contact_point: Point3<f32>,
+    pub contact_normal: Vector3<f32>,
+    // Force the spring pushed the car with on the last step.
+    pub suspension_force: f32,
+
+    // What the MovementSystem asks this wheel to do.
+    pub drive_force: f32,
+    pub brake_force: f32,
+    // Radians, positive turns left.
+    pub steer_angle: f32,
+    // Handbrake stops the wheel from spinning at all.
+    pub locked: bool,
+
+    // Updated every step by the TireSystem.
+    pub slip_ratio: f32,
+    pub slip_angle: f32,
+    pub longitudinal_force: f32,
+    pub lateral_force: f32,
+}
+
+impl Wheel {
+    pub fn new(mount: Point3<f32>, axle: Axle, driven: bool, rest_length: f32, radius: f32, stiffness: f32, damping: f32) -> Wheel {
+        Wheel {
+            mount,
+            axle,
+            driven,
+            // Only the front wheels turn.
+            steered: axle == Axle::Front,
+            rest_length,
+            radius,
+            stiffness,
+            damping,
+
+            compression: 0.0,
+            in_contact: false,
+            grounded: false,
+            surface: None,
+            material: SurfaceMaterial::Asphalt,
+            contact_point: point![0.0, 0.0, 0.0],
+            contact_normal: vector![0.0, 1.0, 0.0],
+            suspension_force: 0.0,
+
+            drive_force: 0.0,
+            brake_force: 0.0,
+            steer_angle: 0.0,
+            locked: false,
+
+            slip_ratio: 0.0,
+            slip_angle: 0.0,
+            longitudinal_force: 0.0,
+            lateral_force: 0.0,
+        }
+    }
+    pub fn compression_ratio(&self) -> f32 {
+        // 0.0 is fully extended, 1.0 is fully squished.
+        self.compression / self.rest_length
+    }
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct WheelSet {
+    // Front left, front right, rear left, rear right.
+    pub wheels: Vec<Wheel>,
+}
+
+impl WheelSet {
+    pub fn any_grounded(&self) -> bool {
+        self.wheels.iter().any(|wheel| wheel.grounded)
+    }
+}
+
+// Simplified Pacejka "magic formula". Returns how much of the
+// load a tire can turn into force for a given slip.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct PacejkaCurve {
+    // Stiffness, shape, peak and curvature factors.
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub e: f32,
+}
+
+impl PacejkaCurve {
+    pub fn evaluate(&self, slip: f32) -> f32 {
+        let bx = self.b * slip;
+        self.d * (self.c * (bx - self.e * (bx - bx.atan())).atan()).sin()
+    }
+    pub fn stiffness(&self) -> f32 {
+        // Slope of the curve at zero slip.
+        self.b * self.c * self.d
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TireParams {
+    // Force along the wheel from the slip ratio.
+    pub longitudinal: PacejkaCurve,
+    // Force across the wheel from the slip angle (radians).
+    pub lateral: PacejkaCurve,
+}
+
+// Tires of a car. Can be loaded from JSON to tune the handling.
+#[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Tires {
+    pub front: TireParams,
+    pub rear: TireParams,
+}
+
+impl Tires {
+    pub fn for_axle(&self, axle: Axle) -> &TireParams {
+        match axle {
+            Axle::Front => &self.front,
+            Axle::Rear => &self.rear,
+        }
+    }
+}
+
+impl Default for Tires {
+    fn default() -> Self {
+        let longitudinal = PacejkaCurve { b: 10.0, c: 1.65, d: 1.0, e: 0.97 };
+        Tires {
+            front: TireParams {
+                longitudinal,
+                lateral: PacejkaCurve { b: 9.0, c: 1.3, d: 1.0, e: -0.5 },
+            },
+            // Slightly less grip on the back so the car can drift.
+            rear: TireParams {
+                longitudinal,
+                lateral: PacejkaCurve { b: 9.0, c: 1.3, d: 0.9, e: -0.5 },
+            },
+        }
+    }
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Steering {
+    // Wheel angle (radians) at full lock while stopped.
+    pub max_angle: f32,
+    // Wheel angle (radians) at full lock at high speed.
+    pub min_angle: f32,
+    // Speed (m/s) where the angle reaches min_angle.
+    pub high_speed: f32,
+    // How fast the steering moves towards the input, in full locks per second.
+    pub steer_rate: f32,
+    // How fast it goes back to the center when letting go.
+    pub return_rate: f32,
+
+    // Smoothed steering input from -1.0 (right) to 1.0 (left).
+    pub input: f32,
+    // Current angle of the steered wheels.
+    pub angle: f32,
+}
+
+impl Steering {
+    pub fn max_angle_at(&self, speed: f32) -> f32 {
+        // Shrink the max angle the faster we go.
+        let t = (speed.abs() / self.high_speed).min(1.0);
+        self.max_angle + (self.min_angle - self.max_angle) * t
+    }
+    pub fn update(&mut self, target: f32, speed: f32, dt: f32) {
+        // Ramp the input towards the target instead of snapping to it.
+        // Going back counts when letting go or steering across the center,
+        // not when starting to steer from it.
+        let returning = target == 0.0 || (self.input != 0.0 && target.signum() != self.input.signum());
+        let rate = if returning { self.return_rate } else { self.steer_rate };
+        let max_change = rate * dt;
+        self.input += (target - self.input).clamp(-max_change, max_change);
+
+        self.angle = self.input * self.max_angle_at(speed);
+    }
+}
+
+impl Default for Steering {
+    fn default() -> Self {
+        Steering {
+            max_angle: 0.6,
+            min_angle: 0.1,
+            high_speed: 40.0,
+            steer_rate: 3.0,
+            return_rate: 5.0,
+
+            input: 0.0,
+            angle: 0.0,
+        }
+    }
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Engine {
+    // Points of (rpm, torque in Nm), sorted by rpm.
+    pub torque_curve: Vec<(f32, f32)>,
+    pub idle_rpm: f32,
+    // Fuel gets cut above this rpm.
+    pub redline_rpm: f32,
+
+    // Updated every step by the EngineSystem.
+    pub rpm: f32,
+    pub throttle: f32,
+    // Force the wheels push the car forward with.
+    pub drive_force: f32,
+}
+
+impl Engine {
+    pub fn torque_at(&self, rpm: f32) -> f32 {
+        // Linear interpolation between the points of the torque curve.
+        let curve = &self.torque_curve;
+        if curve.is_empty() {
+            return 0.0;
+        }
+        if rpm <= curve[0].0 {
+            return curve[0].1;
+        }
+        for points in curve.windows(2) {
+            let (low, high) = (points[0], points[1]);
+            if rpm <= high.0 {
+                let t = (rpm - low.0) / (high.0 - low.0);
+                return low.1 + (high.1 - low.1) * t;
+            }
+        }
+        curve[curve.len() - 1].1
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            torque_curve: vec![
+                (1000.0, 90.0),
+                (2500.0, 130.0),
+                (4500.0, 150.0),
+                (6000.0, 140.0),
+                (7000.0, 110.0),
+            ],
+            idle_rpm: 900.0,
+            redline_rpm: 7000.0,
+
+            rpm: 900.0,
+            throttle: 0.0,
+            drive_force: 0.0,
+        }
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GearboxMode {
+    Automatic,
+    Manual,
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Gearbox {
+    pub mode: GearboxMode,
+    // Ratios of the forward gears, first gear first.
+    pub ratios: Vec<f32>,
+    pub reverse_ratio: f32,
+    pub final_drive: f32,
+    // Seconds without power while changing gears.
+    pub shift_time: f32,
+    // Used by the automatic mode.
+    pub upshift_rpm: f32,
+    pub downshift_rpm: f32,
+
+    // -1 is reverse, 0 is neutral and 1.. are the forward gears.
+    pub gear: i32,
+    pub shift_timer: f32,
+    // Remember the shift keys so holding them only shifts once.
+    pub shift_up_held: bool,
+    pub shift_down_held: bool,
+}
+
+impl Gearbox {
+    pub fn ratio(&self) -> f32 {
+        // Total ratio between the engine and the wheels, negative in reverse.
+        let gear_ratio = match self.gear {
+            0 => 0.0,
+            gear if gear < 0 => -self.reverse_ratio,
+            gear => self.ratios[(gear - 1) as usize],
+        };
+        gear_ratio * self.final_drive
+    }
+    pub fn is_shifting(&self) -> bool {
+        self.shift_timer > 0.0
+    }
+    pub fn shift_to(&mut self, gear: i32) {
+        // Clamp to the gears we have and start the shift.
+        let gear = gear.clamp(-1, self.ratios.len() as i32);
+        if gear != self.gear {
+            self.gear = gear;
+            self.shift_timer = self.shift_time;
+        }
+    }
+}
+
+impl Default for Gearbox {
+    fn default() -> Self {
+        Gearbox {
+            mode: GearboxMode::Automatic,
+            ratios: vec![3.2, 2.1, 1.5, 1.15, 0.9],
+            reverse_ratio: 3.0,
+            final_drive: 3.4,
+            shift_time: 0.25,
+            upshift_rpm: 6500.0,
+            downshift_rpm: 2500.0,
+
+            gear: 1,
+            shift_timer: 0.0,
+            shift_up_held: false,
+            shift_down_held: false,
+        }
+    }
+}
+
+// What a trigger volume is for on a track.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerKind {
+    // The index in the order the checkpoints have to be driven through.
+    Checkpoint(u32),
+    FinishLine,
+    BoostPad,
+    KillZone,
+    Respawn,
+}
+
+// A non solid region backed by a sensor collider.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Trigger {
+    pub kind: TriggerKind,
+    // Ids of the entities currently inside, kept by the TriggerSystem.
+    pub occupants: Vec<u32>,
+}
+impl Trigger {
+    pub fn new(kind: TriggerKind) -> Trigger {
+        Trigger {
+            kind,
+            occupants: Vec::new(),
+        }
+    }
+    pub fn contains(&self, entity: u32) -> bool {
+        self.occupants.contains(&entity)
+    }
+}
+
+// How far a car is into the race. Times are seconds since the green light.
+#[derive(Component, Clone, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct RaceProgress {
+    // Starts at 1, goes past the lap count once the car finished.
+    pub lap: u32,
+    // Index of the checkpoint the car has to drive through next.
+    pub next_checkpoint: u32,
+    pub lap_start: f32,
+    pub current_lap: f32,
+    pub last_lap: Option<f32>,
+    pub best_lap: Option<f32>,
+    // Time into the lap at every checkpoint passed this lap.
+    pub splits: Vec<f32>,
+    pub last_splits: Vec<f32>,
+    pub best_splits: Vec<f32>,
+    // The car drove through a checkpoint it already passed.
+    pub wrong_way: bool,
+    // The car reached a checkpoint or the finish while skipping one.
+    pub missed_checkpoint: bool,
+    // Place in the race, 1 is first.
+    pub position: u32,
+    pub finish_time: Option<f32>,
+}
+impl RaceProgress {
+    pub fn new() -> RaceProgress {
+        RaceProgress {
+            lap: 1,
+            position: 1,
+            ..RaceProgress::default()
+        }
+    }
+    pub fn finished(&self) -> bool {
+        self.finish_time.is_some()
+    }
+    pub fn pass_checkpoint(&mut self, time: f32) {
+        self.splits.push(time - self.lap_start);
+        self.next_checkpoint += 1;
+        self.wrong_way = false;
+        self.missed_checkpoint = false;
+    }
+    pub fn complete_lap(&mut self, time: f32, total_laps: u32) {
+        let lap_time = time - self.lap_start;
+        if self.best_lap.is_none_or(|best| lap_time < best) {
+            self.best_lap = Some(lap_time);
+            self.best_splits = self.splits.clone();
+        }
+        self.last_lap = Some(lap_time);
+        self.last_splits = std::mem::take(&mut self.splits);
+
+        self.lap += 1;
+        self.next_checkpoint = 0;
+        self.lap_start = time;
+        self.current_lap = 0.0;
+        self.wrong_way = false;
+        self.missed_checkpoint = false;
+
+        if self.lap > total_laps {
+            self.finish_time = Some(time);
+        }
+    }
+}
+
+// What the driver of a car is asking for this step. Humans get
+// the input of their player slot, AI drivers write their own.
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct ControlInput(pub GameInput);
+
+// Which local player drives this car, 0 is the first player.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct PlayerSlot(pub u32);
+
+// Drives a car around a loop of waypoints, through the same
+// ControlInput a human would use.
+#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct AiDriver {
+    pub waypoints: Vec<Point3<f32>>,
+    // Index of the waypoint the car is heading to.
+    pub target: usize,
+    // How far ahead of the car it aims, in meters.
+    pub lookahead: f32,
+    // A waypoint is reached once the car is this close.
+    pub waypoint_radius: f32,
+    pub max_speed: f32,
+    // Sideways acceleration it takes corners with.
+    pub corner_grip: f32,
+    // Deceleration it plans its braking with.
+    pub braking: f32,
+}
+impl AiDriver {
+    pub fn new(waypoints: Vec<Point3<f32>>, skill: f32) -> AiDriver {
+        // A skill of 0.0 drives carefully, 1.0 as fast as the car allows.
+        let skill = skill.clamp(0.0, 1.0);
+        AiDriver {
+            waypoints,
+            target: 0,
+            lookahead: 10.0 + 6.0 * skill,
+            waypoint_radius: 8.0,
+            max_speed: 20.0 + 20.0 * skill,
+            corner_grip: 5.0 + 7.0 * skill,
+            braking: 6.0 + 6.0 * skill,
+        }
+    }
+}
+
+// Where a car goes back to when it gets stuck, kept by the RecoverySystem.
+#[derive(Component, Clone, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Recovery {
+    // The last checkpoint or respawn trigger the car drove through,
+    // and the direction the car was heading (yaw) when it did.
+    pub respawn_point: Option<Point3<f32>>,
+    pub respawn_yaw: f32,
+    // Seconds the car has been stuck on its side or roof.
+    pub upside_down: f32,
+    // Holding the reset key only resets once.
+    pub reset_held: bool,
+}
+
+// Replays the best lap. It has no colliders, so nothing can hit it.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct GhostCar {}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct ModelName {
     pub name: [char; 5],
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steering_from_center_is_symmetric() {
+        let mut left = Steering::default();
+        let mut right = Steering::default();
+        for _ in 0..10 {
+            left.update(1.0, 5.0, 1.0 / 60.0);
+            right.update(-1.0, 5.0, 1.0 / 60.0);
+            assert_eq!(left.angle, -right.angle);
+        }
+        assert!(left.angle > 0.0);
+    }
+
+    #[test]
+    fn material_at_finds_the_closest_cell() {
+        use SurfaceMaterial::*;
+        // Two rows along z, three columns along x.
+        let grid = [[Asphalt, Dirt, Grass], [Ice, Mud, Sand]];
+        let map = TerrainMap {
+            materials: DMatrix::from_fn(2, 3, |row, column| grid[row][column]),
+            scale: vector![30.0, 1.0, 20.0],
+        };
+
+        assert_eq!(map.material_at(&point![-15.0, 0.0, -10.0]), Asphalt);
+        assert_eq!(map.material_at(&point![15.0, 0.0, -10.0]), Grass);
+        assert_eq!(map.material_at(&point![-15.0, 0.0, 10.0]), Ice);
+        assert_eq!(map.material_at(&point![1.0, 0.0, 6.0]), Mud);
+        // Past the edges, the border cells go on.
+        assert_eq!(map.material_at(&point![500.0, 3.0, 500.0]), Sand);
+        assert_eq!(map.material_at(&point![-500.0, 0.0, -4.0]), Asphalt);
+    }
+}
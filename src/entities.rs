@@ -1,12 +1,30 @@
 
-use nalgebra::{point, Matrix, DMatrix, dmatrix, vector};
-use parry3d::math::{Vector, Real, Rotation, AngVector};
-use rapier3d::prelude::{RigidBodyBuilder, ColliderBuilder};
-use specs::{Entities, Read, world::EntitiesRes, LazyUpdate, Builder};
+use nalgebra::{point, DMatrix, dmatrix, vector};
+use parry3d::math::{Vector, Real, AngVector, Point};
+use rapier3d::prelude::{RigidBodyBuilder, ColliderBuilder, Collider, ColliderHandle, ActiveEvents};
+use specs::{Entity, Read, world::EntitiesRes, LazyUpdate, Builder};
 
-use crate::{components::{PlayerCar, PhysicsObject, ModelName, PhysicsType}, resources::{ColliderContainer, RigidBodyContainer}};
+use crate::{components::{PlayerCar, PhysicsObject, ModelName, PhysicsType, Wheel, WheelSet, Engine, Gearbox, Tires, Axle, Steering, Trigger, TriggerKind, GhostCar, ControlInput, AiDriver, PlayerSlot, Recovery, SurfaceMaterial, TerrainMap}, resources::{ColliderContainer, RigidBodyContainer}};
 
 
+// Colliders remember the id of their entity in user_data, plus one
+// so that colliders without an entity (user_data 0) can be told apart.
+pub fn link_colliders(entity: Entity, handles: &[ColliderHandle], colliders: &mut ColliderContainer) {
+    for handle in handles {
+        if let Some(collider) = colliders.0.get_mut(*handle) {
+            collider.user_data = entity.id() as u128 + 1;
+        }
+    }
+}
+
+pub fn collider_entity(collider: &Collider) -> Option<u32> {
+    // The entity id stored by link_colliders.
+    match collider.user_data {
+        0 => None,
+        data => Some((data - 1) as u32),
+    }
+}
+
 // Create entity from Read<Lazy> and Entities
 
 pub fn create_player<'a>(
@@ -16,22 +34,66 @@ pub fn create_player<'a>(
 
     // Insert to RigidBodyContainer and ColliderContainer
     pos: Vector<Real>,
+    rot: AngVector<Real>,
+    slot: u32,
     rigidbodies: &mut RigidBodyContainer,
     colliders: &mut ColliderContainer,
 
-) {
+) -> Entity {
+    // Driven by the local player in this slot.
+    let entity = create_car(ent, lazy, pos, rot, ['c', 'a', 'r', '0', '0'], rigidbodies, colliders);
+    lazy.insert(entity, PlayerSlot(slot));
+    entity
+}
+
+pub fn create_ai_car<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    driver: AiDriver,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) -> Entity {
+    // The same car as the player, with a different driver.
+    let entity = create_car(ent, lazy, pos, rot, ['c', 'a', 'r', '0', '1'], rigidbodies, colliders);
+    lazy.insert(entity, driver);
+    entity
+}
+
+fn create_car<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    name: [char; 5],
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) -> Entity {
     // Create the rigidbody and colliders.
     /* Create our rigid body */
     let rigidbody = RigidBodyBuilder::new_dynamic()
         .translation(pos)
+        .rotation(rot)
         .additional_mass(120.0)
-        .linear_damping(1.0)
+        // Low damping, the engine and brakes do the work now.
+        .linear_damping(0.1)
         .angular_damping(0.2)
         .build();
     
     /* Create the colliders (1 for now) */
     let collider = ColliderBuilder::cuboid(4.0, 1.0, 2.0)
         .restitution(0.2)
+        // Report what the car hits, for sounds and sparks.
+        .active_events(ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS)
         .build();
     
     // These are stored in the entity.
@@ -39,20 +101,76 @@ pub fn create_player<'a>(
     // Remember to insert the collider with the parent.
     let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
 
+    // Mount the four wheels a bit above the bottom of the chassis.
+    // The engine pushes through the rear wheels.
+    let (rest_length, radius, stiffness, damping) = (1.0, 0.5, 1500.0, 300.0);
+    let wheels = vec![
+        Wheel::new(point!(3.0, -0.5, -2.0), Axle::Front, false, rest_length, radius, stiffness, damping),
+        Wheel::new(point!(3.0, -0.5, 2.0), Axle::Front, false, rest_length, radius, stiffness, damping),
+        Wheel::new(point!(-3.0, -0.5, -2.0), Axle::Rear, true, rest_length, radius, stiffness, damping),
+        Wheel::new(point!(-3.0, -0.5, 2.0), Axle::Rear, true, rest_length, radius, stiffness, damping),
+    ];
+
     // Create the specs entity.
-    lazy.create_entity(&ent)
+    let entity = lazy.create_entity(ent)
         .with(PlayerCar { touching_ground: false })
+        .with(WheelSet { wheels })
+        .with(Engine::default())
+        .with(Gearbox::default())
+        .with(Tires::default())
+        .with(Steering::default())
+        .with(ControlInput::default())
+        .with(Recovery::default())
+        .with(ModelName { name })
+        .with(PhysicsObject {
+            object_type: PhysicsType::Dynamic,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+
+    entity
+}
+
+pub fn create_ghost<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    rigidbodies: &mut RigidBodyContainer,
+
+) {
+    // Moved by the GhostSystem every step, physics doesn't push it.
+    let rigidbody = RigidBodyBuilder::new_kinematic_position_based()
+        .translation(pos)
+        .rotation(rot)
+        .build();
+
+    // No colliders, the ghost drives through everything.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+
+    // Create the specs entity.
+    lazy.create_entity(ent)
+        .with(GhostCar {})
         .with(ModelName {
-            name: ['c', 'a', 'r', '0', '0'],
+            name: ['g', 'h', 'o', 's', 't'],
         })
         .with(PhysicsObject {
             object_type: PhysicsType::Dynamic,
             rigidbody: rigidbody_handle,
-            colliders: vec![collider_handle],
+            colliders: vec![],
         })
         .build();
 }
 
+// Not used right now, the map comes from create_map or a level.
+#[allow(dead_code)]
 pub fn create_floor<'a>(
     // Get the Builders of the entity:
     ent: &Read<'a, EntitiesRes>,
@@ -60,6 +178,7 @@ pub fn create_floor<'a>(
 
     // Insert to RigidBodyContainer and ColliderContainer
     pos: Vector<Real>,
+    material: SurfaceMaterial,
     rigidbodies: &mut RigidBodyContainer,
     colliders: &mut ColliderContainer,
 
@@ -73,7 +192,7 @@ pub fn create_floor<'a>(
     
     /* Create the floor collider */
     let collider = ColliderBuilder::cuboid(100.0, 0.1, 100.0)
-        .friction(1.0)
+        .friction(material.properties().friction)
         
         .build();
     
@@ -83,16 +202,20 @@ pub fn create_floor<'a>(
         let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
 
     // Create the specs entity.
-    lazy.create_entity(&ent)
+    let entity = lazy.create_entity(ent)
         .with(ModelName {
             name: ['f', 'l', 'o', 'o', 'r'],
         })
+        .with(material)
         .with(PhysicsObject {
             object_type: PhysicsType::Static,
             rigidbody: rigidbody_handle,
             colliders: vec![collider_handle],
         })
         .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
 }
 
 pub fn create_ramp<'a>(
@@ -103,6 +226,7 @@ pub fn create_ramp<'a>(
     // Insert to RigidBodyContainer and ColliderContainer
     pos: Vector<Real>,
     rot: AngVector<Real>,
+    material: SurfaceMaterial,
     rigidbodies: &mut RigidBodyContainer,
     colliders: &mut ColliderContainer,
 
@@ -129,6 +253,7 @@ pub fn create_ramp<'a>(
 
     let collider = ColliderBuilder::convex_hull(&points)
         .unwrap()
+        .friction(material.properties().friction)
         .build();
     
     // These are stored in the entity.
@@ -137,23 +262,30 @@ pub fn create_ramp<'a>(
         let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
 
     // Create the specs entity.
-    lazy.create_entity(&ent)
+    let entity = lazy.create_entity(ent)
         .with(ModelName {
             name: ['r', 'a', 'm', 'p', '0'],
         })
+        .with(material)
         .with(PhysicsObject {
             object_type: PhysicsType::Static,
             rigidbody: rigidbody_handle,
             colliders: vec![collider_handle],
         })
         .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
 }
 
+#[allow(dead_code)]
 pub fn create_ground_mesh<'a> (
         // Get the Builders of the entity:
         ent: &Read<'a, EntitiesRes>,
         lazy: &Read<'a, LazyUpdate>,
     
+        material: SurfaceMaterial,
+
         // Insert to RigidBodyContainer and ColliderContainer
         rigidbodies: &mut RigidBodyContainer,
         colliders: &mut ColliderContainer,
@@ -170,7 +302,9 @@ pub fn create_ground_mesh<'a> (
     ];
 
     // Use the heights to create the heightmap collider
-    let collider = ColliderBuilder::heightfield(heights, vector![1000.0, 100.0, 1000.0]).build();
+    let collider = ColliderBuilder::heightfield(heights, vector![1000.0, 100.0, 1000.0])
+        .friction(material.properties().friction)
+        .build();
 
     // Create the handles for the entity.
     let rigidbody_handle = rigidbodies.0.insert(rigidbody);
@@ -178,14 +312,267 @@ pub fn create_ground_mesh<'a> (
     let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
 
     // Create the specs entity.
-    lazy.create_entity(&ent)
+    let entity = lazy.create_entity(ent)
+        .with(ModelName {
+            name: ['m', 'a', 'p', '0', '0'],
+        })
+        .with(material)
+        .with(PhysicsObject {
+            object_type: PhysicsType::Static,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_box<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    half_extents: Vector<Real>,
+    material: SurfaceMaterial,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) {
+    // Create the rigidbody and colliders.
+    /* Create our rigid body */
+    let rigidbody = RigidBodyBuilder::new_static()
+        .translation(pos)
+        .rotation(rot)
+        .build();
+
+    /* Create the box collider */
+    let collider = ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z)
+        .friction(material.properties().friction)
+        .build();
+
+    // These are stored in the entity.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+    // Remember to insert the collider with the parent.
+    let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
+
+    // Create the specs entity.
+    let entity = lazy.create_entity(ent)
+        .with(ModelName {
+            name: ['b', 'o', 'x', '0', '0'],
+        })
+        .with(material)
+        .with(PhysicsObject {
+            object_type: PhysicsType::Static,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_convex_hull<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    points: &[Point<Real>],
+    material: SurfaceMaterial,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) -> Option<()> {
+    // Create the rigidbody and colliders.
+    /* Create our rigid body */
+    let rigidbody = RigidBodyBuilder::new_static()
+        .translation(pos)
+        .rotation(rot)
+        .build();
+
+    /* Create the hull collider, this fails if the points are all flat. */
+    let collider = ColliderBuilder::convex_hull(points)?
+        .friction(material.properties().friction)
+        .build();
+
+    // These are stored in the entity.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+    // Remember to insert the collider with the parent.
+    let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
+
+    // Create the specs entity.
+    let entity = lazy.create_entity(ent)
+        .with(ModelName {
+            name: ['h', 'u', 'l', 'l', '0'],
+        })
+        .with(material)
+        .with(PhysicsObject {
+            object_type: PhysicsType::Static,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+
+    Some(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_trimesh<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    vertices: Vec<Point<Real>>,
+    indices: Vec<[u32; 3]>,
+    material: SurfaceMaterial,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) {
+    // Create the rigidbody and colliders.
+    /* Create our rigid body */
+    let rigidbody = RigidBodyBuilder::new_static()
+        .translation(pos)
+        .rotation(rot)
+        .build();
+
+    /* Create the mesh collider */
+    let collider = ColliderBuilder::trimesh(vertices, indices)
+        .friction(material.properties().friction)
+        .build();
+
+    // These are stored in the entity.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+    // Remember to insert the collider with the parent.
+    let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
+
+    // Create the specs entity.
+    let entity = lazy.create_entity(ent)
+        .with(ModelName {
+            name: ['m', 'e', 's', 'h', '0'],
+        })
+        .with(material)
+        .with(PhysicsObject {
+            object_type: PhysicsType::Static,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_heightfield<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    heights: DMatrix<Real>,
+    scale: Vector<Real>,
+    material: SurfaceMaterial,
+    // Material of every height, `material` everywhere without it.
+    materials: Option<DMatrix<SurfaceMaterial>>,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+) {
+    // Create the rigidbody for our map.
+    let rigidbody = RigidBodyBuilder::new_static().build();
+
+    // Use the heights to create the heightmap collider
+    let collider = ColliderBuilder::heightfield(heights, scale)
+        .friction(material.properties().friction)
+        .build();
+
+    // Create the handles for the entity.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+    // Remember to insert the collider with the parent.
+    let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
+
+    // Create the specs entity.
+    let entity = lazy.create_entity(ent)
         .with(ModelName {
             name: ['m', 'a', 'p', '0', '0'],
         })
+        .with(material)
         .with(PhysicsObject {
             object_type: PhysicsType::Static,
             rigidbody: rigidbody_handle,
             colliders: vec![collider_handle],
         })
         .build();
-}
\ No newline at end of file
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+
+    if let Some(materials) = materials {
+        lazy.insert(entity, TerrainMap { materials, scale });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_trigger<'a>(
+    // Get the Builders of the entity:
+    ent: &Read<'a, EntitiesRes>,
+    lazy: &Read<'a, LazyUpdate>,
+
+    // Insert to RigidBodyContainer and ColliderContainer
+    pos: Vector<Real>,
+    rot: AngVector<Real>,
+    half_extents: Vector<Real>,
+    kind: TriggerKind,
+    rigidbodies: &mut RigidBodyContainer,
+    colliders: &mut ColliderContainer,
+
+) {
+    // Create the rigidbody and colliders.
+    /* Create our rigid body */
+    let rigidbody = RigidBodyBuilder::new_static()
+        .translation(pos)
+        .rotation(rot)
+        .build();
+
+    /* Create the sensor, cars drive through it but we hear about it. */
+    let collider = ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z)
+        .sensor(true)
+        .active_events(ActiveEvents::INTERSECTION_EVENTS)
+        .build();
+
+    // These are stored in the entity.
+    let rigidbody_handle = rigidbodies.0.insert(rigidbody);
+    // Remember to insert the collider with the parent.
+    let collider_handle = colliders.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbodies.0);
+
+    // Create the specs entity.
+    let entity = lazy.create_entity(ent)
+        .with(Trigger::new(kind))
+        .with(ModelName {
+            name: ['t', 'r', 'i', 'g', '0'],
+        })
+        .with(PhysicsObject {
+            object_type: PhysicsType::Static,
+            rigidbody: rigidbody_handle,
+            colliders: vec![collider_handle],
+        })
+        .build();
+
+    // Let the collision events find this entity.
+    link_colliders(entity, &[collider_handle], colliders);
+}
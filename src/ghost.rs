@@ -0,0 +1,207 @@
+use std::fmt;
+
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use serde::{Serialize, Deserialize};
+
+// A recorded lap, one frame per fixed tick.
+//
+// Binary layout, little endian:
+//   "GHST"              magic
+//   u8                  format version
+//   f32                 seconds per frame
+//   f32                 lap time
+//   u32                 frame count
+//   per frame:
+//     f32 x3            position
+//     i16 x4            rotation quaternion (i, j, k, w), scaled to i16
+
+const MAGIC: &[u8; 4] = b"GHST";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4 + 1 + 4 + 4 + 4;
+const FRAME_SIZE: usize = 3 * 4 + 4 * 2;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GhostFrame {
+    pub position: [f32; 3],
+    pub rotation: [f32; 4],
+}
+
+impl GhostFrame {
+    pub fn from_isometry(isometry: &Isometry3<f32>) -> GhostFrame {
+        let translation = isometry.translation.vector;
+        let rotation = isometry.rotation.quaternion().coords;
+        GhostFrame {
+            position: [translation.x, translation.y, translation.z],
+            rotation: [rotation.x, rotation.y, rotation.z, rotation.w],
+        }
+    }
+
+    pub fn to_isometry(self) -> Isometry3<f32> {
+        let [x, y, z] = self.position;
+        let [i, j, k, w] = self.rotation;
+        Isometry3::from_parts(
+            Translation3::new(x, y, z),
+            UnitQuaternion::from_quaternion(Quaternion::new(w, i, j, k)),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Ghost {
+    // Seconds between frames, the fixed timestep it was recorded with.
+    pub frame_time: f32,
+    pub lap_time: f32,
+    pub frames: Vec<GhostFrame>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GhostError {
+    // Doesn't start with the magic bytes.
+    NotAGhost,
+    UnsupportedVersion(u8),
+    // Shorter than the header says.
+    Truncated,
+    // Parsed, but with times we can't play back.
+    Invalid(String),
+}
+
+impl fmt::Display for GhostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GhostError::NotAGhost => write!(f, "Not a ghost file"),
+            GhostError::UnsupportedVersion(version) => write!(f, "Unsupported ghost version {}", version),
+            GhostError::Truncated => write!(f, "Ghost data is truncated"),
+            GhostError::Invalid(message) => write!(f, "Invalid ghost: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for GhostError {}
+
+impl Ghost {
+    pub fn frame(&self, index: usize) -> Option<&GhostFrame> {
+        // Past the end the ghost waits at the finish.
+        self.frames.get(index).or_else(|| self.frames.last())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.frames.len() * FRAME_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.frame_time.to_le_bytes());
+        bytes.extend_from_slice(&self.lap_time.to_le_bytes());
+        bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+
+        for frame in self.frames.iter() {
+            for value in frame.position.iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in frame.rotation.iter() {
+                let quantized = (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                bytes.extend_from_slice(&quantized.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Ghost, GhostError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(GhostError::NotAGhost);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(GhostError::Truncated);
+        }
+        if bytes[4] != VERSION {
+            return Err(GhostError::UnsupportedVersion(bytes[4]));
+        }
+
+        let f32_at = |offset: usize| f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let i16_at = |offset: usize| i16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        let frame_time = f32_at(5);
+        let lap_time = f32_at(9);
+        let frame_count = u32::from_le_bytes([bytes[13], bytes[14], bytes[15], bytes[16]]) as usize;
+
+        if !(frame_time.is_finite() && frame_time > 0.0) {
+            return Err(GhostError::Invalid("frame time must be positive".to_string()));
+        }
+        if !(lap_time.is_finite() && lap_time >= 0.0) {
+            return Err(GhostError::Invalid("lap time must not be negative".to_string()));
+        }
+        // Checked before allocating, the count comes from the file.
+        if (bytes.len() - HEADER_SIZE) / FRAME_SIZE < frame_count {
+            return Err(GhostError::Truncated);
+        }
+
+        let mut frames = Vec::with_capacity(frame_count);
+        for index in 0..frame_count {
+            let offset = HEADER_SIZE + index * FRAME_SIZE;
+            let position = [f32_at(offset), f32_at(offset + 4), f32_at(offset + 8)];
+            if position.iter().any(|value| !value.is_finite()) {
+                return Err(GhostError::Invalid(format!("frame {} has a non finite position", index)));
+            }
+            let rotation_offset = offset + 12;
+            let mut rotation = [0.0; 4];
+            for (component, value) in rotation.iter_mut().enumerate() {
+                *value = i16_at(rotation_offset + component * 2) as f32 / i16::MAX as f32;
+            }
+            if rotation.iter().all(|value| *value == 0.0) {
+                return Err(GhostError::Invalid(format!("frame {} has no rotation", index)));
+            }
+            frames.push(GhostFrame { position, rotation });
+        }
+
+        Ok(Ghost {
+            frame_time,
+            lap_time,
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ghost() -> Ghost {
+        let frames = (0..3)
+            .map(|index| {
+                let rotation = UnitQuaternion::from_euler_angles(0.0, index as f32 * 0.5, 0.0);
+                GhostFrame::from_isometry(&Isometry3::from_parts(Translation3::new(index as f32, 1.0, -2.0), rotation))
+            })
+            .collect();
+        Ghost {
+            frame_time: 1.0 / 60.0,
+            lap_time: 12.5,
+            frames,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let ghost = ghost();
+        let loaded = Ghost::from_bytes(&ghost.to_bytes()).unwrap();
+        assert_eq!((loaded.frame_time, loaded.lap_time), (ghost.frame_time, ghost.lap_time));
+        assert_eq!(loaded.frames.len(), ghost.frames.len());
+        for (loaded, frame) in loaded.frames.iter().zip(ghost.frames.iter()) {
+            assert_eq!(loaded.position, frame.position);
+            // Rotations are quantized to i16.
+            let angle = loaded.to_isometry().rotation.angle_to(&frame.to_isometry().rotation);
+            assert!(angle < 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let bytes = ghost().to_bytes();
+        assert_eq!(Ghost::from_bytes(b"RPLY\x01").unwrap_err(), GhostError::NotAGhost);
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(Ghost::from_bytes(&newer).unwrap_err(), GhostError::UnsupportedVersion(VERSION + 1));
+
+        for length in [HEADER_SIZE - 1, bytes.len() - 1] {
+            assert_eq!(Ghost::from_bytes(&bytes[..length]).unwrap_err(), GhostError::Truncated);
+        }
+    }
+}
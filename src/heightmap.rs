@@ -0,0 +1,257 @@
+use std::fmt;
+
+use nalgebra::DMatrix;
+
+// Reads heightmaps from grayscale images, black is 0.0 and white 1.0.
+// Seen from above, the image's x goes along x and its y along z.
+//
+// Supported: PNG (8 and 16 bit gray, with or without alpha) and
+// PGM, binary (P5) or text (P2), up to 16 bit.
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+// Biggest side we build a collider for.
+const MAX_SIDE: usize = 4097;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeightmapError {
+    // Neither a PNG nor a PGM.
+    UnknownFormat,
+    // A color image, heights need one channel.
+    NotGrayscale,
+    // Shorter than the header says.
+    Truncated,
+    // The png crate couldn't decode it.
+    Png(String),
+    // Decoded, but not something we can build a map from.
+    Invalid(String),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeightmapError::UnknownFormat => write!(f, "Heightmaps must be PNG or PGM images"),
+            HeightmapError::NotGrayscale => write!(f, "Heightmap images must be grayscale"),
+            HeightmapError::Truncated => write!(f, "Heightmap image is truncated"),
+            HeightmapError::Png(message) => write!(f, "Failed to decode PNG: {}", message),
+            HeightmapError::Invalid(message) => write!(f, "Invalid heightmap: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+pub fn decode(bytes: &[u8]) -> Result<DMatrix<f32>, HeightmapError> {
+    // Rows along z, columns along x, like rapier and the arrays given to create_map.
+    let heights = if bytes.starts_with(PNG_SIGNATURE) {
+        decode_png(bytes)?
+    } else if bytes.starts_with(b"P5") || bytes.starts_with(b"P2") {
+        decode_pgm(bytes)?
+    } else {
+        return Err(HeightmapError::UnknownFormat);
+    };
+
+    let (rows, columns) = heights.shape();
+    if rows < 2 || columns < 2 {
+        return Err(HeightmapError::Invalid("the image needs at least 2x2 pixels".to_string()));
+    }
+    if rows > MAX_SIDE || columns > MAX_SIDE {
+        return Err(HeightmapError::Invalid(format!("the image can't be bigger than {0}x{0} pixels", MAX_SIDE)));
+    }
+    Ok(heights)
+}
+
+fn decode_png(bytes: &[u8]) -> Result<DMatrix<f32>, HeightmapError> {
+    let png_error = |err: png::DecodingError| HeightmapError::Png(err.to_string());
+
+    let mut decoder = png::Decoder::new(bytes);
+    // Gray images with less than 8 bits come out as 8 bits.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(png_error)?;
+
+    // Only the gray channel counts, alpha is ignored.
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        _ => return Err(HeightmapError::NotGrayscale),
+    };
+    let sixteen_bit = info.bit_depth == png::BitDepth::Sixteen;
+    let sample_size = if sixteen_bit { 2 } else { 1 };
+    let max = if sixteen_bit { u16::MAX as f32 } else { u8::MAX as f32 };
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut heights = DMatrix::zeros(height, width);
+    for y in 0..height {
+        let line = &buffer[y * info.line_size..];
+        for x in 0..width {
+            let offset = x * channels * sample_size;
+            // PNG stores 16 bit samples big endian.
+            let value = if sixteen_bit {
+                u16::from_be_bytes([line[offset], line[offset + 1]]) as f32
+            } else {
+                line[offset] as f32
+            };
+            heights[(y, x)] = value / max;
+        }
+    }
+    Ok(heights)
+}
+
+fn decode_pgm(bytes: &[u8]) -> Result<DMatrix<f32>, HeightmapError> {
+    let binary = bytes.starts_with(b"P5");
+    let mut position = 2;
+
+    // The header is magic, width, height and the white value,
+    // separated by whitespace and comments.
+    let mut header = [0usize; 3];
+    for value in header.iter_mut() {
+        *value = next_number(bytes, &mut position)?;
+    }
+    let [width, height, max] = header;
+    if max == 0 || max > u16::MAX as usize {
+        return Err(HeightmapError::Invalid(format!("unsupported white value {}", max)));
+    }
+    if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(HeightmapError::Invalid(format!("unsupported size {}x{}", width, height)));
+    }
+
+    let mut heights = DMatrix::zeros(height, width);
+    if binary {
+        // A single whitespace, then the pixels. Two bytes each past 255, big endian.
+        position += 1;
+        let sample_size = if max > u8::MAX as usize { 2 } else { 1 };
+        let pixels = bytes.get(position..).unwrap_or_default();
+        if pixels.len() < width * height * sample_size {
+            return Err(HeightmapError::Truncated);
+        }
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (y * width + x) * sample_size;
+                let value = match sample_size {
+                    2 => u16::from_be_bytes([pixels[offset], pixels[offset + 1]]) as usize,
+                    _ => pixels[offset] as usize,
+                };
+                heights[(y, x)] = value.min(max) as f32 / max as f32;
+            }
+        }
+    } else {
+        for y in 0..height {
+            for x in 0..width {
+                let value = next_number(bytes, &mut position)?;
+                heights[(y, x)] = value.min(max) as f32 / max as f32;
+            }
+        }
+    }
+    Ok(heights)
+}
+
+fn next_number(bytes: &[u8], position: &mut usize) -> Result<usize, HeightmapError> {
+    // Skip whitespace and comments, then read the digits.
+    loop {
+        match bytes.get(*position) {
+            Some(byte) if byte.is_ascii_whitespace() => *position += 1,
+            Some(b'#') => {
+                while bytes.get(*position).is_some_and(|byte| *byte != b'\n') {
+                    *position += 1;
+                }
+            }
+            Some(_) => break,
+            None => return Err(HeightmapError::Truncated),
+        }
+    }
+
+    let start = *position;
+    while bytes.get(*position).is_some_and(|byte| byte.is_ascii_digit()) {
+        *position += 1;
+    }
+    if start == *position {
+        return Err(HeightmapError::Invalid("expected a number in the image".to_string()));
+    }
+    std::str::from_utf8(&bytes[start..*position])
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| HeightmapError::Invalid("number too big in the image".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P2: &[u8] = include_bytes!("../tests/fixtures/heightmap_p2.pgm");
+    const P5: &[u8] = include_bytes!("../tests/fixtures/heightmap_p5.pgm");
+
+    fn png(width: u32, height: u32, color: png::ColorType, depth: png::BitDepth, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, width, height);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            encoder.write_header().unwrap().write_image_data(data).unwrap();
+        }
+        bytes
+    }
+
+    fn assert_gradient(heights: &DMatrix<f32>) {
+        // Both fixtures go from black to white, 3 wide and 2 tall.
+        assert_eq!(heights.shape(), (2, 3));
+        let expected = [[0.0, 0.2, 0.4], [0.6, 0.8, 1.0]];
+        for (row, values) in expected.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                assert!((heights[(row, column)] - value).abs() < 1.0e-6, "at ({}, {})", row, column);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_pgm() {
+        assert_gradient(&decode(P2).unwrap());
+        assert_gradient(&decode(P5).unwrap());
+    }
+
+    #[test]
+    fn decodes_png() {
+        let eight_bit = png(3, 2, png::ColorType::Grayscale, png::BitDepth::Eight, &[0, 51, 102, 153, 204, 255]);
+        assert_gradient(&decode(&eight_bit).unwrap());
+
+        let sixteen_bit: Vec<u8> = [0u16, 13107, 26214, 39321, 52428, 65535].iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        let sixteen_bit = png(3, 2, png::ColorType::Grayscale, png::BitDepth::Sixteen, &sixteen_bit);
+        assert_gradient(&decode(&sixteen_bit).unwrap());
+
+        let with_alpha = png(3, 2, png::ColorType::GrayscaleAlpha, png::BitDepth::Eight, &[
+            0, 9, 51, 9, 102, 9, 153, 9, 204, 9, 255, 9,
+        ]);
+        assert_gradient(&decode(&with_alpha).unwrap());
+    }
+
+    #[test]
+    fn rejects_color_png() {
+        let color = png(2, 2, png::ColorType::Rgb, png::BitDepth::Eight, &[0; 12]);
+        assert_eq!(decode(&color).unwrap_err(), HeightmapError::NotGrayscale);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert_eq!(decode(b"GIF89a").unwrap_err(), HeightmapError::UnknownFormat);
+        assert_eq!(decode(b"").unwrap_err(), HeightmapError::UnknownFormat);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        assert_eq!(decode(&P5[..P5.len() - 1]).unwrap_err(), HeightmapError::Truncated);
+        assert_eq!(decode(&P2[..P2.len() - 5]).unwrap_err(), HeightmapError::Truncated);
+        assert_eq!(decode(b"P5\n3 2").unwrap_err(), HeightmapError::Truncated);
+
+        let eight_bit = png(3, 2, png::ColorType::Grayscale, png::BitDepth::Eight, &[0; 6]);
+        assert!(matches!(decode(&eight_bit[..eight_bit.len() / 2]), Err(HeightmapError::Png(_))));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        assert!(matches!(decode(b"P2 1 1 255 0"), Err(HeightmapError::Invalid(_))));
+        assert!(matches!(decode(b"P5 5000 2 255 "), Err(HeightmapError::Invalid(_))));
+        assert!(matches!(decode(b"P2 2 2 0 0 0 0 0"), Err(HeightmapError::Invalid(_))));
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt;
+
+use nalgebra::{DMatrix, point};
+use rapier3d::prelude::ColliderBuilder;
+use serde::{Serialize, Deserialize};
+
+use crate::{components::{TriggerKind, SurfaceMaterial}, terrain::{self, TerrainParams}};
+
+// Levels can be written in JSON or RON, both map to these structs.
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Level {
+    #[serde(default)]
+    pub metadata: LevelMetadata,
+    #[serde(default)]
+    pub terrain: Option<TerrainDef>,
+    pub spawn_points: Vec<SpawnPoint>,
+    #[serde(default)]
+    pub props: Vec<PropDef>,
+    // In the order they have to be driven through.
+    #[serde(default)]
+    pub checkpoints: Vec<CheckpointDef>,
+    // Finish lines, boost pads, kill zones and respawn areas.
+    #[serde(default)]
+    pub triggers: Vec<TriggerDef>,
+    // Loop of points the AI drives along, the checkpoints when empty.
+    #[serde(default)]
+    pub racing_line: Vec<[f32; 3]>,
+    // AI cars, placed on the spawn points after the player's.
+    #[serde(default)]
+    pub opponents: Vec<OpponentDef>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LevelMetadata {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub version: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TerrainDef {
+    // Rows of heights along z, each going along x. Same layout as the array given to create_map.
+    #[serde(default)]
+    pub heights: Vec<Vec<f32>>,
+    // Generate the heights instead, see TerrainParams.
+    #[serde(default)]
+    pub generator: Option<TerrainParams>,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_terrain_scale")]
+    pub scale: [f32; 3],
+    #[serde(default)]
+    pub material: SurfaceMaterial,
+    // Optional material of every height, same layout. Overrides `material`.
+    #[serde(default)]
+    pub materials: Vec<Vec<SurfaceMaterial>>,
+}
+
+fn default_terrain_scale() -> [f32; 3] {
+    [1000.0, 100.0, 1000.0]
+}
+
+impl TerrainDef {
+    pub fn to_matrix(&self) -> DMatrix<f32> {
+        if let Some(params) = &self.generator {
+            return terrain::generate(self.seed, params, self.scale);
+        }
+
+        // Row y, column x of the level ends up at (y, x), like create_map.
+        let length = self.heights.len();
+        let width = self.heights.first().map_or(0, |row| row.len());
+        let mut matrix = DMatrix::from_element(length, width, 0.0);
+        for (y, row) in self.heights.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                matrix[(y, x)] = *value;
+            }
+        }
+        matrix
+    }
+    pub fn to_material_matrix(&self) -> Option<DMatrix<SurfaceMaterial>> {
+        // Laid out the same way as the heights.
+        if self.materials.is_empty() {
+            return None;
+        }
+        let length = self.materials.len();
+        let width = self.materials.first().map_or(0, |row| row.len());
+        let mut matrix = DMatrix::from_element(length, width, self.material);
+        for (y, row) in self.materials.iter().enumerate() {
+            for (x, material) in row.iter().enumerate() {
+                matrix[(y, x)] = *material;
+            }
+        }
+        Some(matrix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SpawnPoint {
+    pub position: [f32; 3],
+    // Rotation around the up axis, in radians.
+    #[serde(default)]
+    pub yaw: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PropDef {
+    Ramp {
+        position: [f32; 3],
+        #[serde(default)]
+        rotation: [f32; 3],
+        #[serde(default)]
+        material: SurfaceMaterial,
+    },
+    Box {
+        position: [f32; 3],
+        #[serde(default)]
+        rotation: [f32; 3],
+        half_extents: [f32; 3],
+        #[serde(default)]
+        material: SurfaceMaterial,
+    },
+    ConvexHull {
+        position: [f32; 3],
+        #[serde(default)]
+        rotation: [f32; 3],
+        points: Vec<[f32; 3]>,
+        #[serde(default)]
+        material: SurfaceMaterial,
+    },
+    Trimesh {
+        position: [f32; 3],
+        #[serde(default)]
+        rotation: [f32; 3],
+        vertices: Vec<[f32; 3]>,
+        indices: Vec<[u32; 3]>,
+        #[serde(default)]
+        material: SurfaceMaterial,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CheckpointDef {
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    pub half_extents: [f32; 3],
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TriggerDef {
+    pub kind: TriggerKind,
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    pub half_extents: [f32; 3],
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct OpponentDef {
+    // 0.0 to 1.0, see AiDriver.
+    #[serde(default = "default_skill")]
+    pub skill: f32,
+}
+
+fn default_skill() -> f32 {
+    0.5
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelError {
+    // The text isn't valid JSON or RON for a level.
+    Parse(String),
+    // The level parsed, but describes something we can't build.
+    Invalid(String),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelError::Parse(message) => write!(f, "Failed to parse level: {}", message),
+            LevelError::Invalid(message) => write!(f, "Invalid level: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LevelError {}
+
+impl Level {
+    pub fn parse(source: &str) -> Result<Level, LevelError> {
+        // JSON always starts with a brace, anything else is treated as RON.
+        let level: Level = if source.trim_start().starts_with('{') {
+            serde_json::from_str(source).map_err(|err| LevelError::Parse(err.to_string()))?
+        } else {
+            ron::from_str(source).map_err(|err| LevelError::Parse(err.to_string()))?
+        };
+        level.validate()?;
+        Ok(level)
+    }
+
+    pub fn validate(&self) -> Result<(), LevelError> {
+        fn invalid(message: String) -> Result<(), LevelError> {
+            Err(LevelError::Invalid(message))
+        }
+        fn finite(values: &[f32]) -> bool {
+            values.iter().all(|value| value.is_finite())
+        }
+        fn positive(values: &[f32]) -> bool {
+            values.iter().all(|value| value.is_finite() && *value > 0.0)
+        }
+
+        if self.spawn_points.is_empty() {
+            return invalid("the level needs at least one spawn point".to_string());
+        }
+        for (index, spawn) in self.spawn_points.iter().enumerate() {
+            if !finite(&spawn.position) || !spawn.yaw.is_finite() {
+                return invalid(format!("spawn point {} has a non finite position", index));
+            }
+        }
+
+        if let Some(terrain) = &self.terrain {
+            // Rows and the length of every row.
+            let (rows, width) = match &terrain.generator {
+                Some(params) => {
+                    if !terrain.heights.is_empty() {
+                        return invalid("terrain can't have both heights and a generator".to_string());
+                    }
+                    params.validate().map_err(LevelError::Invalid)?;
+                    (params.size, params.size)
+                }
+                None => {
+                    let width = terrain.heights.first().map_or(0, |row| row.len());
+                    if terrain.heights.len() < 2 || width < 2 {
+                        return invalid("terrain needs at least 2x2 heights".to_string());
+                    }
+                    if terrain.heights.iter().any(|row| row.len() != width) {
+                        return invalid("terrain rows must all have the same length".to_string());
+                    }
+                    if terrain.heights.iter().any(|row| !finite(row)) {
+                        return invalid("terrain has non finite heights".to_string());
+                    }
+                    (terrain.heights.len(), width)
+                }
+            };
+            if !positive(&terrain.scale) {
+                return invalid("terrain scale must be positive".to_string());
+            }
+            let same_size = terrain.materials.len() == rows
+                && terrain.materials.iter().all(|row| row.len() == width);
+            if !terrain.materials.is_empty() && !same_size {
+                return invalid("terrain materials must have the same size as the heights".to_string());
+            }
+        }
+
+        for (index, prop) in self.props.iter().enumerate() {
+            let (position, rotation) = prop.transform();
+            if !finite(&position) || !finite(&rotation) {
+                return invalid(format!("prop {} has a non finite transform", index));
+            }
+            match prop {
+                PropDef::Ramp { .. } => {}
+                PropDef::Box { half_extents, .. } => {
+                    if !positive(half_extents) {
+                        return invalid(format!("box {} needs positive half extents", index));
+                    }
+                }
+                PropDef::ConvexHull { points, .. } => {
+                    let points: Vec<_> = points.iter().map(|p| point![p[0], p[1], p[2]]).collect();
+                    if ColliderBuilder::convex_hull(&points).is_none() {
+                        return invalid(format!("convex hull {} can't be built from its points", index));
+                    }
+                }
+                PropDef::Trimesh { vertices, indices, .. } => {
+                    if vertices.is_empty() || indices.is_empty() {
+                        return invalid(format!("trimesh {} is empty", index));
+                    }
+                    if vertices.iter().any(|vertex| !finite(vertex)) {
+                        return invalid(format!("trimesh {} has non finite vertices", index));
+                    }
+                    let out_of_range = indices.iter()
+                        .flatten()
+                        .any(|&vertex| vertex as usize >= vertices.len());
+                    if out_of_range {
+                        return invalid(format!("trimesh {} has indices out of range", index));
+                    }
+                }
+            }
+        }
+
+        for (index, checkpoint) in self.checkpoints.iter().enumerate() {
+            if !finite(&checkpoint.position) || !finite(&checkpoint.rotation) {
+                return invalid(format!("checkpoint {} has a non finite transform", index));
+            }
+            if !positive(&checkpoint.half_extents) {
+                return invalid(format!("checkpoint {} needs positive half extents", index));
+            }
+        }
+
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            if !finite(&trigger.position) || !finite(&trigger.rotation) {
+                return invalid(format!("trigger {} has a non finite transform", index));
+            }
+            if !positive(&trigger.half_extents) {
+                return invalid(format!("trigger {} needs positive half extents", index));
+            }
+            // Checkpoints are numbered by their place in the checkpoints list.
+            if let TriggerKind::Checkpoint(_) = trigger.kind {
+                return invalid(format!("trigger {} is a checkpoint, put it in checkpoints instead", index));
+            }
+        }
+
+        if self.racing_line.iter().any(|point| !finite(point)) {
+            return invalid("racing line has non finite points".to_string());
+        }
+        if !self.opponents.is_empty() {
+            if self.opponents.len() >= self.spawn_points.len() {
+                return invalid(format!(
+                    "{} opponents need {} spawn points", self.opponents.len(), self.opponents.len() + 1
+                ));
+            }
+            if self.ai_waypoints().len() < 2 {
+                return invalid("opponents need a racing line or at least 2 checkpoints".to_string());
+            }
+            if self.opponents.iter().any(|opponent| !(0.0..=1.0).contains(&opponent.skill)) {
+                return invalid("opponent skill must be between 0 and 1".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn ai_waypoints(&self) -> Vec<[f32; 3]> {
+        // The racing line, or the checkpoints in order without one.
+        if self.racing_line.is_empty() {
+            self.checkpoints.iter().map(|checkpoint| checkpoint.position).collect()
+        } else {
+            self.racing_line.clone()
+        }
+    }
+}
+
+impl PropDef {
+    pub fn transform(&self) -> ([f32; 3], [f32; 3]) {
+        // Position and rotation of any prop.
+        match self {
+            PropDef::Ramp { position, rotation, .. }
+            | PropDef::Box { position, rotation, .. }
+            | PropDef::ConvexHull { position, rotation, .. }
+            | PropDef::Trimesh { position, rotation, .. } => (*position, *rotation),
+        }
+    }
+    pub fn material(&self) -> SurfaceMaterial {
+        match self {
+            PropDef::Ramp { material, .. }
+            | PropDef::Box { material, .. }
+            | PropDef::ConvexHull { material, .. }
+            | PropDef::Trimesh { material, .. } => *material,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_example_level() {
+        let level = Level::parse(include_str!("../www/resources/levels/ramps.ron")).unwrap();
+        assert_eq!(level.metadata.name, "Ramps");
+        assert_eq!(level.spawn_points.len(), 2);
+        assert_eq!(level.props.len(), 3);
+        assert_eq!(level.checkpoints.len(), 3);
+        assert_eq!(level.terrain.unwrap().heights.len(), 5);
+        assert_eq!(level.racing_line.len(), 8);
+        assert_eq!(level.opponents.len(), 1);
+    }
+
+    #[test]
+    fn materials_default_to_asphalt() {
+        let level = Level::parse(include_str!("../www/resources/levels/ramps.ron")).unwrap();
+        assert_eq!(level.props[0].material(), SurfaceMaterial::Asphalt);
+        assert_eq!(level.props[1].material(), SurfaceMaterial::Dirt);
+        assert_eq!(level.terrain.unwrap().material, SurfaceMaterial::Asphalt);
+
+        let level = Level::parse(r#"{
+            "spawn_points": [{ "position": [0.0, 5.0, 0.0] }],
+            "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "material": "ice" },
+            "props": [{ "box": { "position": [0.0, 0.0, 0.0], "half_extents": [1.0, 1.0, 1.0], "material": "mud" } }]
+        }"#).unwrap();
+        assert_eq!(level.terrain.unwrap().material, SurfaceMaterial::Ice);
+        assert_eq!(level.props[0].material(), SurfaceMaterial::Mud);
+        assert!(matches!(Level::parse(r#"{
+            "spawn_points": [{ "position": [0.0, 5.0, 0.0] }],
+            "props": [{ "box": { "position": [0.0, 0.0, 0.0], "half_extents": [1.0, 1.0, 1.0], "material": "lava" } }]
+        }"#), Err(LevelError::Parse(_))));
+    }
+
+    #[test]
+    fn parses_json() {
+        let level = Level::parse(r#"{ "spawn_points": [{ "position": [1.0, 2.0, 3.0] }] }"#).unwrap();
+        assert_eq!(level.spawn_points[0].position, [1.0, 2.0, 3.0]);
+        assert!(level.terrain.is_none());
+    }
+
+    #[test]
+    fn terrain_materials_follow_the_heights() {
+        let level = Level::parse(r#"{
+            "spawn_points": [{ "position": [0.0, 5.0, 0.0] }],
+            "terrain": { "heights": [[0.0, 0.0, 0.2], [0.0, 0.0, 0.0]], "materials": [["ice", "ice", "mud"], ["ice", "ice", "ice"]] }
+        }"#).unwrap();
+        let terrain = level.terrain.unwrap();
+        let heights = terrain.to_matrix();
+        let materials = terrain.to_material_matrix().unwrap();
+        assert_eq!(materials.shape(), heights.shape());
+
+        // The mud lands on the same cell as the raised height.
+        let raised = heights.iter().position(|height| *height == 0.2);
+        let mud = materials.iter().position(|material| *material == SurfaceMaterial::Mud);
+        assert_eq!(mud, raised);
+    }
+
+    #[test]
+    fn terrain_rows_go_along_z() {
+        // Two rows of three, like an image two pixels tall.
+        let level = Level::parse(r#"{
+            "spawn_points": [{ "position": [0.0, 5.0, 0.0] }],
+            "terrain": { "heights": [[0.0, 0.0, 0.0], [0.3, 0.0, 0.0]], "materials": [["ice", "ice", "mud"], ["ice", "ice", "ice"]] }
+        }"#).unwrap();
+        let terrain = level.terrain.unwrap();
+        let heights = terrain.to_matrix();
+        assert_eq!(heights.shape(), (2, 3));
+        assert_eq!(heights[(1, 0)], 0.3);
+        assert_eq!(terrain.to_material_matrix().unwrap()[(0, 2)], SurfaceMaterial::Mud);
+    }
+
+    #[test]
+    fn rejects_broken_source() {
+        let source = include_str!("../www/resources/levels/ramps.ron");
+        assert!(matches!(Level::parse(&source[..source.len() / 2]), Err(LevelError::Parse(_))));
+        assert!(matches!(Level::parse("{ \"spawn_points\": "), Err(LevelError::Parse(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_levels() {
+        let invalid = [
+            r#"{ "spawn_points": [] }"#,
+            r#"{ "spawn_points": [{ "position": [0.0, 5.0, 0.0] }], "terrain": { "heights": [[0.0, 0.0], [0.0]] } }"#,
+            r#"{ "spawn_points": [{ "position": [0.0, 5.0, 0.0] }], "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "scale": [0.0, 1.0, 1.0] } }"#,
+            r#"{ "spawn_points": [{ "position": [0.0, 5.0, 0.0] }], "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "materials": [["ice"]] } }"#,
+            // Every opponent needs its own spawn point.
+            r#"{ "spawn_points": [{ "position": [0.0, 5.0, 0.0] }], "racing_line": [[0.0, 0.0, 0.0], [9.0, 0.0, 0.0]], "opponents": [{ "skill": 0.5 }] }"#,
+        ];
+        for source in invalid.iter() {
+            assert!(matches!(Level::parse(source), Err(LevelError::Invalid(_))), "{}", source);
+        }
+    }
+}
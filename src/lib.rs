@@ -3,17 +3,32 @@ mod components;
 mod systems;
 mod resources;
 mod entities;
+mod level;
+mod ghost;
+mod replay;
+mod snapshot;
+mod terrain;
+mod heightmap;
 
-use components::{ModelName, PhysicsObject, PhysicsType};
-use nalgebra::{Vector3, vector, DMatrix};
-use rapier3d::prelude::{RigidBodySet, ColliderSet, RigidBodyBuilder, ColliderBuilder};
-use resources::{RigidBodyContainer, ColliderContainer};
-use serde::{Serialize};
-use specs::{World, Builder, WorldExt, System, RunNow, Join};
+use components::{ModelName, PhysicsObject, PhysicsType, PreviousTransform, PlayerCar, WheelSet, Engine, Gearbox, GearboxMode, Tires, RaceProgress, TriggerKind, PlayerSlot, SurfaceMaterial, TerrainMap};
+use nalgebra::{vector, DMatrix, Vector3};
+use rapier3d::prelude::{RigidBodyBuilder, ColliderBuilder, RigidBodyHandle, ColliderHandle};
+use resources::{RigidBodyContainer, ColliderContainer, SimulationClock, LoadedLevel, GameRng, GameEvents, RaceSession, GhostRecorder, ReplayRecorder, PlayerInputs, PhysicsResource};
+use serde::{Serialize, Deserialize};
+use specs::{World, WorldExt, RunNow, Join, Builder, Entity, Entities, LazyUpdate, Read, Write};
 
-use js_sys::{Array, Float32Array, JsString, Object};
+#[cfg(feature = "web")]
+use js_sys::Array;
 
-use systems::{init::InitSystem};
+use systems::{init::InitSystem, level::LevelLoadSystem};
+
+pub use level::{Level, LevelError};
+pub use ghost::{Ghost, GhostError};
+pub use replay::{Replay, ReplayError, ReplayCommand};
+pub use snapshot::SnapshotError;
+pub use terrain::{TerrainParams, TerrainMesh};
+pub use heightmap::HeightmapError;
+#[cfg(feature = "web")]
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -22,28 +37,40 @@ use wasm_bindgen::prelude::*;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-#[wasm_bindgen]
+// Seconds between starting a race and the green light.
+const RACE_COUNTDOWN: f32 = 3.0;
+// Local players that can share the screen.
+const MAX_PLAYERS: u32 = 4;
+// Sideways distance between players sharing a spawn point.
+const PLAYER_SPACING: f32 = 8.0;
+// Size of the maps made with create_map and generate_map, heights go from 0.0 to 1.0.
+const MAP_SCALE: [f32; 3] = [1000.0, 100.0, 1000.0];
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
 pub struct GameContainer {
     world: World, //specs
 }
 
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
 impl GameContainer {
     pub fn create() -> GameContainer {
-        // The Specs world contains our Resources and Entites.
-        let mut world = World::new();
-
-        // Insert the resources
-        resources::insert_resources(&mut world);
-
-        // Register the components
-        components::register_components(&mut world);
+        // Pick a different world every time.
+        GameContainer::create_with_seed(utils::random_seed())
+    }
 
+    pub fn create_with_seed(seed: u64) -> GameContainer {
+        // The same seed always creates the same world.
+        // The Specs world contains our Resources and Entites.
+        let mut world = GameContainer::empty_world();
+        world.insert(GameRng::new(seed));
+        world.insert(ReplayRecorder {
+            replay: Replay::new(seed, None),
+        });
 
         // Run the setup system to spawn our player and floor.
         let mut is = InitSystem {};
-        is.run_now(&mut world);
+        is.run_now(&world);
 
         // Apply the changes done with LazyUpdate to our world.
         world.maintain();
@@ -52,14 +79,88 @@ impl GameContainer {
             world,
         }
     }
-    pub fn run_systems(&mut self, keys: &GameKeysContainer) {
-        // Update the keys resource.
+
+    pub fn seed(&self) -> u64 {
+        // Seed the current world was generated with.
+        self.world.read_resource::<GameRng>().seed
+    }
+
+    pub fn run_systems(&mut self, keys: &GameKeysContainer, elapsed: f32) {
+        // Keyboards only have on/off keys, map them to the analog input.
+        self.run_systems_with_input(&keys.to_input(), elapsed);
+    }
+
+    pub fn run_systems_with_input(&mut self, input: &GameInput, elapsed: f32) {
+        // Elapsed is the wall time since the last call, in seconds.
+        // A single input drives the first player.
+        self.world.write_resource::<PlayerInputs>().slots = vec![*input];
+        // Run the systems.
+        systems::run_systems(&mut self.world, elapsed);
+    }
+
+    pub fn run_systems_multi(&mut self, inputs: &GameInputSet, elapsed: f32) {
+        // Same as run_systems_with_input, with an input for every player slot.
+        self.world.write_resource::<PlayerInputs>().slots = inputs.inputs.clone();
+        systems::run_systems(&mut self.world, elapsed);
+    }
+
+    pub fn step_with_input(&mut self, input: &GameInput) {
+        // Run exactly one fixed physics step, no matter the wall time.
+        self.world.write_resource::<PlayerInputs>().slots = vec![*input];
+        systems::run_fixed_tick(&mut self.world);
+        // Nothing to blend, log_entities should show the step we just ran.
+        self.world.write_resource::<SimulationClock>().alpha = 1.0;
+    }
+
+    pub fn step_multi(&mut self, inputs: &GameInputSet) {
+        self.world.write_resource::<PlayerInputs>().slots = inputs.inputs.clone();
+        systems::run_fixed_tick(&mut self.world);
+        self.world.write_resource::<SimulationClock>().alpha = 1.0;
+    }
+
+    pub fn add_player(&mut self) -> Option<u32> {
+        // Spawn a car for another local player, returns its slot.
+        // None once every slot is taken.
+        let slot = self.player_count();
+        if slot >= MAX_PLAYERS {
+            return None;
+        }
+        self.record_command(ReplayCommand::AddPlayer);
+
+        let (pos, rot) = self.spawn_for_slot(slot);
         {
-            let mut keys_resource = self.world.write_resource::<GameKeysContainer>();
-            keys_resource.keys = keys.keys;
+            let (entities, lazy, mut rigidbodies, mut colliders) = self.world.system_data::<(
+                Entities,
+                Read<LazyUpdate>,
+                Write<RigidBodyContainer>,
+                Write<ColliderContainer>,
+            )>();
+            entities::create_player(&entities, &lazy, pos, rot, slot, &mut rigidbodies, &mut colliders);
+        }
+        self.world.maintain();
+        Some(slot)
+    }
+
+    pub fn player_count(&self) -> u32 {
+        self.world.read_storage::<PlayerSlot>().join().count() as u32
+    }
+
+    pub fn player_entity(&self, slot: u32) -> Option<u32> {
+        // Entity id of a player's car, to point their camera at.
+        self.player_car(slot).map(|entity| entity.id())
+    }
+
+    pub fn tick(&self) -> u64 {
+        // Physics steps simulated since the world was created.
+        self.world.read_resource::<SimulationClock>().tick
+    }
+
+    pub fn take_events(&mut self) -> GameEventContainer {
+        // Every event since the last call, for sounds and particles.
+        let mut events = self.world.write_resource::<GameEvents>();
+        GameEventContainer {
+            data: std::mem::take(&mut events.outbox),
         }
-        // Run the systems.
-        systems::run_systems(&mut self.world);
     }
 
     pub fn log_entities(&self) -> GameObjectContainer {
@@ -69,25 +170,34 @@ impl GameContainer {
         // Fetch Components
         let names = self.world.read_storage::<ModelName>();
         let physics_objects = self.world.read_storage::<PhysicsObject>();
+        let previous_transforms = self.world.read_storage::<PreviousTransform>();
         let entities = self.world.entities();
 
         // Fetch rigidbodies.
         let rigidbody_set = self.world.read_resource::<RigidBodyContainer>();
-        
+
+        // How far we are between the last two physics steps.
+        let alpha = self.world.read_resource::<SimulationClock>().alpha;
 
         // Find all entites with these components.
-        for (name, ps_object, entity) in (&names, &physics_objects, &entities).join() {
+        for (name, ps_object, previous, entity) in (&names, &physics_objects, previous_transforms.maybe(), &entities).join() {
             // Use the object's rigidbody handle to find the rigidbody.
             let rigidbody = rigidbody_set.0.get(ps_object.rigidbody).unwrap();
 
+            // Blend between the last two steps so the movement looks smooth.
+            let position = match previous {
+                Some(previous) => previous.0.lerp_slerp(rigidbody.position(), alpha),
+                None => *rigidbody.position(),
+            };
+
             // Get pos and rot from the rigidbody.
-            let pos: Vector3<f32> = rigidbody.position().translation.vector.xyz();
-            let rot = rigidbody.rotation().euler_angles();
+            let pos: Vector3<f32> = position.translation.vector.xyz();
+            let rot = position.rotation.euler_angles();
 
             // Form the Object
             let object: GameObject = GameObject {
                 name: name.name,
-                physics: ps_object.object_type.clone(),
+                physics: ps_object.object_type,
                 id: entity.id(),
                 pos: [pos[0], pos[1], pos[2]],
                 rot: [rot.0, rot.1, rot.2],
@@ -101,29 +211,486 @@ impl GameContainer {
         gameobject_container
     }
 
-    pub fn create_map(&mut self, heightmap: Array) {
-        // Create the rigidbody for our map.
-        let rigidbody = RigidBodyBuilder::new_static().build();
+    pub fn map_heights(&self) -> Vec<f32> {
+        // Heights of the map's collider row after row, laid out like the
+        // array given to create_map: rows go along z, heights in a row along x,
+        // however the map was made.
+        let collider_set = self.world.read_resource::<ColliderContainer>();
+        let heightfield = self.map_entities().first()
+            .and_then(|(_entity, _rigidbody, collider)| collider_set.0.get(*collider))
+            .and_then(|collider| collider.shape().as_heightfield().map(|heightfield| heightfield.heights().clone()));
 
-        // Get the rigidbody and colliders set to add this object later to the simulation.
-        let mut rigidbody_set = self.world.write_resource::<RigidBodyContainer>();
-        let mut collider_set = self.world.write_resource::<ColliderContainer>();
+        // The matrix is stored column by column, transposing it puts the rows first.
+        heightfield.map_or_else(Vec::new, |heights| heights.transpose().as_slice().to_vec())
+    }
+
+    pub fn map_mesh(&self) -> TerrainMesh {
+        // Vertices, normals, uvs and triangles of the map in world space,
+        // built from the collider so the drawing can't drift away from it.
+        let collider_set = self.world.read_resource::<ColliderContainer>();
+        let collider = self.map_entities().first()
+            .and_then(|(_entity, _rigidbody, collider)| collider_set.0.get(*collider));
+
+        match collider.and_then(|collider| collider.shape().as_heightfield().map(|heightfield| (collider, heightfield))) {
+            Some((collider, heightfield)) => TerrainMesh::build(heightfield.heights(), heightfield.scale(), collider.position()),
+            None => TerrainMesh::default(),
+        }
+    }
+
+    pub fn map_scale(&self) -> Vec<f32> {
+        // Width, height and length of the current map, images can be
+        // loaded at any scale. Without a map, the one create_map uses.
+        let collider_set = self.world.read_resource::<ColliderContainer>();
+        self.map_entities().first()
+            .and_then(|(_entity, _rigidbody, collider)| collider_set.0.get(*collider))
+            .and_then(|collider| collider.shape().as_heightfield())
+            .map_or_else(|| MAP_SCALE.to_vec(), |heightfield| heightfield.scale().as_slice().to_vec())
+    }
+
+    pub fn map_width(&self) -> u32 {
+        // Heights in every row of map_heights, along x.
+        let collider_set = self.world.read_resource::<ColliderContainer>();
+        self.map_entities().first()
+            .and_then(|(_entity, _rigidbody, collider)| collider_set.0.get(*collider))
+            .and_then(|collider| collider.shape().as_heightfield())
+            .map_or(0, |heightfield| heightfield.ncols() as u32)
+    }
+
+    pub fn wheel_compressions(&self) -> Vec<f32> {
+        // Return how squished each of the first player's wheels is (0.0 to 1.0).
+        let wheel_sets = self.world.read_storage::<WheelSet>();
+
+        let compressions: Vec<f32> = match self.player_car(0).and_then(|car| wheel_sets.get(car)) {
+            Some(wheel_set) => wheel_set.wheels.iter()
+                .map(|wheel| wheel.compression_ratio())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        compressions
+    }
+
+    pub fn wheel_surfaces(&self) -> Vec<i32> {
+        // Return the entity each of the first player's wheels stands on,
+        // -1 for wheels in the air or against a wall.
+        let wheel_sets = self.world.read_storage::<WheelSet>();
+
+        match self.player_car(0).and_then(|car| wheel_sets.get(car)) {
+            Some(wheel_set) => wheel_set.wheels.iter()
+                .map(|wheel| match (wheel.grounded, wheel.surface) {
+                    (true, Some(surface)) => surface as i32,
+                    _ => -1,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn wheel_materials(&self) -> Vec<u32> {
+        // Return what each of the first player's wheels drives on, see
+        // SurfaceMaterial. For tire sounds and the dust behind the car.
+        let wheel_sets = self.world.read_storage::<WheelSet>();
+
+        match self.player_car(0).and_then(|car| wheel_sets.get(car)) {
+            Some(wheel_set) => wheel_set.wheels.iter()
+                .map(|wheel| wheel.material as u32)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn car_state(&self) -> CarState {
+        self.car_state_of(0)
+    }
+
+    pub fn car_state_of(&self, slot: u32) -> CarState {
+        // Return a player's engine data for the tachometer and engine sound.
+        let physics_objects = self.world.read_storage::<PhysicsObject>();
+        let engines = self.world.read_storage::<Engine>();
+        let gearboxes = self.world.read_storage::<Gearbox>();
+        let rigidbody_set = self.world.read_resource::<RigidBodyContainer>();
+
+        let mut state = CarState::default();
+        let car = self.player_car(slot)
+            .and_then(|car| Some((physics_objects.get(car)?, engines.get(car)?, gearboxes.get(car)?)));
+        if let Some((ps_object, engine, gearbox)) = car {
+            state.rpm = engine.rpm;
+            state.throttle = engine.throttle;
+            state.gear = gearbox.gear;
+            if let Some(rigidbody) = rigidbody_set.0.get(ps_object.rigidbody) {
+                state.speed = rigidbody.linvel().magnitude();
+            }
+        }
+        state
+    }
+
+    pub fn start_race(&mut self, laps: u32) {
+        // Put every car back at the start of a new race and begin the countdown.
+        self.record_command(ReplayCommand::StartRace(laps));
+        {
+            let loaded = self.world.read_resource::<LoadedLevel>();
+            let level = &loaded.level;
+            let has_finish_line = level.triggers.iter().any(|trigger| trigger.kind == TriggerKind::FinishLine);
+
+            let mut race = self.world.write_resource::<RaceSession>();
+            *race = RaceSession {
+                phase: RacePhase::Countdown,
+                laps: laps.max(1),
+                countdown: RACE_COUNTDOWN,
+                time: 0.0,
+                checkpoint_count: level.checkpoints.len() as u32,
+                has_finish_line,
+            };
+        }
+
+        // A new race starts a new recording, the ghost stays.
+        self.world.write_resource::<GhostRecorder>().restart();
+
+        let entities = self.world.entities();
+        let players = self.world.read_storage::<PlayerCar>();
+        let mut progresses = self.world.write_storage::<RaceProgress>();
+        for (_player, entity) in (&players, &entities).join() {
+            progresses.insert(entity, RaceProgress::new()).ok();
+        }
+    }
+
+    pub fn race_state(&self) -> RaceState {
+        self.race_state_of(0)
+    }
 
-        // Create a Dynamic Matrix using the length of the hightmap.
-        let width = heightmap.length() as usize;
-        let mut dynamic_heightmap = DMatrix::from_element(width, width, 0.0);
+    pub fn race_state_of(&self, slot: u32) -> RaceState {
+        // Everything the HUD shows about a player's race.
+        let race = self.world.read_resource::<RaceSession>();
+        let progresses = self.world.read_storage::<RaceProgress>();
+
+        let mut state = RaceState {
+            phase: race.phase,
+            countdown: race.countdown,
+            time: race.time,
+            total_laps: race.laps,
+            checkpoint_count: race.checkpoint_count,
+            racers: progresses.join().count() as u32,
+            progress: RaceProgress::new(),
+        };
+        if let Some(progress) = self.player_car(slot).and_then(|car| progresses.get(car)) {
+            state.progress = progress.clone();
+        }
+        state
+    }
+
+    pub fn export_replay(&self) -> Vec<u8> {
+        // Everything needed to simulate this session again, for bug reports.
+        let mut replay = self.world.read_resource::<ReplayRecorder>().replay.clone();
+        replay.state_hash = self.state_hash();
+        match replay.to_bytes() {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log(&format!("Can't export the replay: {}", err));
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        // FNV-1a over the tick and every moving body, two runs that
+        // ended in the same place have the same hash.
+        let rigidbody_set = self.world.read_resource::<RigidBodyContainer>();
+
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut add = |bits: u64| {
+            for byte in bits.to_le_bytes().iter() {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        add(self.tick());
+        for (_handle, rigidbody) in rigidbody_set.0.iter().filter(|(_, rigidbody)| rigidbody.is_dynamic()) {
+            let position = rigidbody.position();
+            let values = position.translation.vector.iter()
+                .chain(position.rotation.coords.iter())
+                .chain(rigidbody.linvel().iter())
+                .chain(rigidbody.angvel().iter());
+            for value in values {
+                add(value.to_bits() as u64);
+            }
+        }
+        hash
+    }
+
+    pub fn export_ghost(&self) -> Vec<u8> {
+        // The best lap as bytes to share, empty without a ghost.
+        match &self.world.read_resource::<GhostRecorder>().best {
+            Some(ghost) => ghost.to_bytes(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn ghost_lap_time(&self) -> Option<f32> {
+        self.world.read_resource::<GhostRecorder>().best.as_ref().map(|ghost| ghost.lap_time)
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        // The whole game as bytes, load_state puts it back exactly.
+        match snapshot::save(&self.world) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log(&format!("Can't save the game: {}", err));
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn set_gearbox_mode(&mut self, mode: GearboxMode) {
+        // Switch the player's gearbox between automatic and manual.
+        self.record_command(ReplayCommand::SetGearboxMode(mode));
+        let players = self.world.read_storage::<PlayerCar>();
+        let slots = self.world.read_storage::<PlayerSlot>();
+        let mut gearboxes = self.world.write_storage::<Gearbox>();
+
+        for (_player, _slot, gearbox) in (&players, &slots, &mut gearboxes).join() {
+            gearbox.mode = mode;
+        }
+    }
+
+    pub fn get_tire_params(&self) -> String {
+        // Current tires of the player as JSON, handy as a starting point for tuning.
+        let players = self.world.read_storage::<PlayerCar>();
+        let slots = self.world.read_storage::<PlayerSlot>();
+        let tires = self.world.read_storage::<Tires>();
+
+        match (&players, &slots, &tires).join().next() {
+            Some((_player, _slot, tires)) => serde_json::to_string_pretty(tires).unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+}
+
+// Functions that need the browser.
+#[cfg(feature = "web")]
+#[wasm_bindgen]
+impl GameContainer {
+    pub fn load_level(&mut self, source: &str) -> Result<(), JsValue> {
+        // Replace the current world with a level written in JSON or RON.
+        self.try_load_level(source)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn play_replay(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        // Simulate a recorded session again and check it ends the same way.
+        self.try_play_replay(bytes)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn import_ghost(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        // Race against a ghost someone exported with export_ghost.
+        self.try_import_ghost(bytes)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        // Go back to a game saved with save_state.
+        self.try_load_state(bytes)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn set_tire_params(&mut self, json: &str) -> Result<(), JsValue> {
+        // Tune the player's tires without recompiling, see `Tires` for the format.
+        self.try_set_tire_params(json)
+            .map_err(|err| JsValue::from_str(&format!("Invalid tire parameters: {}", err)))
+    }
+
+    pub fn create_map(&mut self, heightmap: Array, materials: Option<Array>) -> Result<(), JsValue> {
+        // Replace the map, optionally with a grid of SurfaceMaterial numbers
+        // the same size as the heightmap, so roads and grass drive differently.
+        // Rows go along z and the heights in them along x, like looking down
+        // at an image of the map. The rows don't need to be as long as there are rows.
+        let rows = heightmap.length() as usize;
+        if rows < 2 {
+            return Err(JsValue::from_str("The heightmap needs at least 2x2 heights"));
+        }
+        let first_row: Array = heightmap.get(0).into();
+        let columns = first_row.length() as usize;
+        if columns < 2 {
+            return Err(JsValue::from_str("The heightmap needs at least 2x2 heights"));
+        }
+
+        // Check the materials before building anything.
+        let materials = match materials {
+            Some(materials) => Some(GameContainer::material_grid(&materials, rows, columns)?),
+            None => None,
+        };
+
+        // Create a Dynamic Matrix using the size of the hightmap.
+        let mut dynamic_heightmap = DMatrix::from_element(rows, columns, 0.0);
 
         // Iter though the Array to fill the height map.
         for (y, row) in heightmap.iter().enumerate() {
             let row: Array = row.into();
+            if row.length() as usize != columns {
+                return Err(JsValue::from_str(&format!("Heightmap row {} isn't as long as the first", y)));
+            }
             for (x, value) in row.iter().enumerate() {
+                let height = value.as_f64()
+                    .filter(|height| height.is_finite())
+                    .ok_or_else(|| JsValue::from_str(&format!("Invalid height at row {}, column {}", y, x)))?;
                 // Update the dynamic hightmap
-                dynamic_heightmap[(x, y)] = value.as_f64().unwrap() as f32;
+                dynamic_heightmap[(y, x)] = height as f32;
             }
         }
 
+        let [x, y, z] = MAP_SCALE;
+        self.replace_map(dynamic_heightmap, vector![x, y, z], materials);
+        Ok(())
+    }
+
+    pub fn create_map_from_image(&mut self, bytes: &[u8], scale: &[f32]) -> Result<(), JsValue> {
+        // Replace the map with a grayscale PNG or PGM, white being the highest.
+        // `scale` is the map's width, height and length, like map_scale.
+        let scale = match scale {
+            [x, y, z] => [*x, *y, *z],
+            _ => return Err(JsValue::from_str("The map scale needs a width, height and length")),
+        };
+        self.try_create_map_from_image(bytes, scale)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn generate_map(&mut self, params: &str) -> Result<(), JsValue> {
+        // Replace the map with terrain generated from the world's seed,
+        // `params` is TerrainParams as JSON. Draw it with map_heights.
+        let params: TerrainParams = serde_json::from_str(params)
+            .map_err(|err| JsValue::from_str(&format!("Invalid terrain parameters: {}", err)))?;
+        self.try_generate_map(&params)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    fn material_grid(materials: &Array, rows: usize, columns: usize) -> Result<DMatrix<SurfaceMaterial>, JsValue> {
+        // Read the material numbers, laid out like the heights.
+        if materials.length() as usize != rows {
+            return Err(JsValue::from_str("The materials must have as many rows as the heightmap"));
+        }
+        let mut grid = DMatrix::from_element(rows, columns, SurfaceMaterial::default());
+        for (y, row) in materials.iter().enumerate() {
+            let row: Array = row.into();
+            if row.length() as usize != columns {
+                return Err(JsValue::from_str(&format!("Material row {} doesn't match the heightmap", y)));
+            }
+            for (x, value) in row.iter().enumerate() {
+                let material = value.as_f64()
+                    .and_then(|index| SurfaceMaterial::from_index(index as u32))
+                    .ok_or_else(|| JsValue::from_str(&format!("Unknown material at row {}, column {}", y, x)))?;
+                grid[(y, x)] = material;
+            }
+        }
+        Ok(grid)
+    }
+}
+
+// Functions that aren't exposed to Javascript.
+impl GameContainer {
+    fn empty_world() -> World {
+        // A world with everything registered, but without entities.
+        let mut world = World::new();
+
+        // Insert the resources
+        resources::insert_resources(&mut world);
+
+        // Register the components
+        components::register_components(&mut world);
+
+        world
+    }
+
+    pub fn try_set_tire_params(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        let new_tires: Tires = serde_json::from_str(json)?;
+        self.record_command(ReplayCommand::SetTireParams(json.to_string()));
+
+        let players = self.world.read_storage::<PlayerCar>();
+        let slots = self.world.read_storage::<PlayerSlot>();
+        let mut tires = self.world.write_storage::<Tires>();
+
+        for (_player, _slot, tires) in (&players, &slots, &mut tires).join() {
+            *tires = new_tires;
+        }
+        Ok(())
+    }
+
+    pub fn try_play_replay(&mut self, bytes: &[u8]) -> Result<(), ReplayError> {
+        let replay = Replay::from_bytes(bytes)?;
+
+        // Build the world the replay started in.
+        let mut game = GameContainer::create_with_seed(replay.seed);
+        if let Some(source) = &replay.level {
+            game.try_load_level(source)
+                .map_err(|err| ReplayError::Invalid(err.to_string()))?;
+        }
+
+        // Apply every command right before the tick it was given on.
+        let mut commands = replay.commands.iter().peekable();
+        for (tick, inputs) in replay.tick_inputs().enumerate() {
+            while let Some((_, command)) = commands.next_if(|(command_tick, _)| *command_tick <= tick as u64) {
+                game.apply_command(command)?;
+            }
+            game.step_multi(&GameInputSet { inputs: inputs.to_vec() });
+        }
+        for (_, command) in commands {
+            game.apply_command(command)?;
+        }
+
+        // Keep the replayed world either way, so a desync can be looked at.
+        let actual = game.state_hash();
+        *self = game;
+        if actual != replay.state_hash {
+            return Err(ReplayError::Desync {
+                expected: replay.state_hash,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn apply_command(&mut self, command: &ReplayCommand) -> Result<(), ReplayError> {
+        match command {
+            ReplayCommand::StartRace(laps) => self.start_race(*laps),
+            ReplayCommand::SetGearboxMode(mode) => self.set_gearbox_mode(*mode),
+            ReplayCommand::SetTireParams(json) => {
+                self.try_set_tire_params(json)
+                    .map_err(|err| ReplayError::Invalid(format!("bad tire parameters: {}", err)))?;
+            }
+            ReplayCommand::SetMap { heights, scale, materials } => {
+                self.replace_map(heights.clone(), Vector3::from(*scale), materials.clone());
+            }
+            ReplayCommand::AddPlayer => {
+                self.add_player()
+                    .ok_or_else(|| ReplayError::Invalid("too many players".to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replace_map(&mut self, heights: DMatrix<f32>, scale: Vector3<f32>, materials: Option<DMatrix<SurfaceMaterial>>) {
+        // Replays rebuild the map from the heights, however it was made.
+        self.record_command(ReplayCommand::SetMap {
+            heights: heights.clone(),
+            scale: [scale.x, scale.y, scale.z],
+            materials: materials.clone(),
+        });
+
+        // Only one map at a time, the old one goes with its collider.
+        self.remove_maps();
+
+        // Create the rigidbody for our map.
+        let rigidbody = RigidBodyBuilder::new_static().build();
+
+        // Get the rigidbody and colliders set to add this object later to the simulation.
+        let mut rigidbody_set = self.world.write_resource::<RigidBodyContainer>();
+        let mut collider_set = self.world.write_resource::<ColliderContainer>();
+
         // Use the heights to create the heightmap collider
-        let collider = ColliderBuilder::heightfield(dynamic_heightmap, vector![1000.0, 100.0, 1000.0]).build();
+        let material = SurfaceMaterial::Asphalt;
+        let collider = ColliderBuilder::heightfield(heights, scale)
+            .friction(material.properties().friction)
+            .build();
 
         // Create the handles for the entity.
         let rigidbody_handle = rigidbody_set.0.insert(rigidbody);
@@ -131,46 +698,208 @@ impl GameContainer {
         let collider_handle = collider_set.0.insert_with_parent(collider, rigidbody_handle, &mut rigidbody_set.0);
 
         // Create an entity that holds the handles.
-        self.world.create_entity_unchecked()
+        let entity = self.world.create_entity_unchecked()
             .with(ModelName {
                 name: ['m', 'a', 'p', '0', '0'],
             })
+            .with(material)
             .with(PhysicsObject {
                 object_type: PhysicsType::Static,
                 rigidbody: rigidbody_handle,
                 colliders: vec![collider_handle],
             })
             .build();
+
+        if let Some(materials) = materials {
+            self.world.write_storage::<TerrainMap>().insert(entity, TerrainMap { materials, scale }).ok();
+        }
+
+        // Let the collision events find this entity.
+        entities::link_colliders(entity, &[collider_handle], &mut collider_set);
+    }
+
+    fn player_car(&self, slot: u32) -> Option<Entity> {
+        let entities = self.world.entities();
+        let slots = self.world.read_storage::<PlayerSlot>();
+        (&entities, &slots).join()
+            .find(|(_entity, player_slot)| player_slot.0 == slot)
+            .map(|(entity, _player_slot)| entity)
     }
-}
 
+    fn spawn_for_slot(&self, slot: u32) -> (Vector3<f32>, Vector3<f32>) {
+        // Extra players use the spawn points after the opponents', or
+        // line up next to the first one when the level runs out.
+        let loaded = self.world.read_resource::<LoadedLevel>();
+        let level = &loaded.level;
 
-#[wasm_bindgen]
+        if let Some(spawn) = level.spawn_points.get(level.opponents.len() + slot as usize) {
+            let [x, y, z] = spawn.position;
+            return (vector![x, y, z], vector![0.0, spawn.yaw, 0.0]);
+        }
+        let ([x, y, z], yaw) = level.spawn_points.first()
+            .map_or(([0.0, 5.0, 0.0], 0.0), |spawn| (spawn.position, spawn.yaw));
+        (vector![x, y, z + PLAYER_SPACING * slot as f32], vector![0.0, yaw, 0.0])
+    }
+
+    fn record_command(&mut self, command: ReplayCommand) {
+        // Commands apply before the next tick runs. Counted in recorded
+        // ticks, the clock's tick can come from a loaded snapshot.
+        let mut recorder = self.world.write_resource::<ReplayRecorder>();
+        let tick = recorder.replay.ticks;
+        recorder.replay.push_command(tick, command);
+    }
+
+    pub fn try_import_ghost(&mut self, bytes: &[u8]) -> Result<(), GhostError> {
+        let ghost = Ghost::from_bytes(bytes)?;
+
+        // Frames are played one per step, so the timestep has to match.
+        let fixed_dt = self.world.read_resource::<SimulationClock>().fixed_dt;
+        if (ghost.frame_time - fixed_dt).abs() > 1e-6 {
+            return Err(GhostError::Invalid(format!(
+                "recorded at {} seconds per frame, the game runs at {}", ghost.frame_time, fixed_dt
+            )));
+        }
+
+        self.world.write_resource::<GhostRecorder>().best = Some(ghost);
+        Ok(())
+    }
+
+    pub fn try_generate_map(&mut self, params: &TerrainParams) -> Result<(), LevelError> {
+        params.validate().map_err(LevelError::Invalid)?;
+        let heights = terrain::generate(self.seed(), params, MAP_SCALE);
+        let [x, y, z] = MAP_SCALE;
+        self.replace_map(heights, vector![x, y, z], None);
+        Ok(())
+    }
+
+    pub fn try_create_map_from_image(&mut self, bytes: &[u8], scale: [f32; 3]) -> Result<(), HeightmapError> {
+        if scale.iter().any(|value| !value.is_finite() || *value <= 0.0) {
+            return Err(HeightmapError::Invalid(format!("the map scale {:?} must be positive", scale)));
+        }
+        let heights = heightmap::decode(bytes)?;
+        let [x, y, z] = scale;
+        self.replace_map(heights, vector![x, y, z], None);
+        Ok(())
+    }
+
+    fn remove_maps(&mut self) {
+        let maps = self.map_entities();
+        {
+            let mut rigidbody_set = self.world.write_resource::<RigidBodyContainer>();
+            let mut collider_set = self.world.write_resource::<ColliderContainer>();
+            let mut physics = self.world.write_resource::<PhysicsResource>();
+            let physics = &mut *physics;
+
+            for (_entity, rigidbody, _collider) in maps.iter() {
+                rigidbody_set.0.remove(*rigidbody, &mut physics.island_manager, &mut collider_set.0, &mut physics.joint_set);
+            }
+        }
+        for (entity, _rigidbody, _collider) in maps {
+            self.world.delete_entity(entity).ok();
+        }
+    }
+
+    fn map_entities(&self) -> Vec<(Entity, RigidBodyHandle, ColliderHandle)> {
+        // Every entity with a heightfield collider.
+        let entities = self.world.entities();
+        let physics_objects = self.world.read_storage::<PhysicsObject>();
+        let collider_set = self.world.read_resource::<ColliderContainer>();
+
+        let mut maps = Vec::new();
+        for (entity, physics_object) in (&entities, &physics_objects).join() {
+            let heightfield = physics_object.colliders.iter().find(|handle| {
+                collider_set.0.get(**handle).is_some_and(|collider| collider.shape().as_heightfield().is_some())
+            });
+            if let Some(collider) = heightfield {
+                maps.push((entity, physics_object.rigidbody, *collider));
+            }
+        }
+        maps
+    }
+
+    pub fn try_load_state(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        // Build the saved world next to the current one, so a broken
+        // save leaves the game as it was.
+        let mut world = GameContainer::empty_world();
+        snapshot::load(bytes, &mut world)?;
+
+        // The replay recorder comes back as it was saved, so replays go on
+        // from the save as if nothing happened after it.
+        self.world = world;
+        Ok(())
+    }
+
+    pub fn try_load_level(&mut self, source: &str) -> Result<(), LevelError> {
+        // Check everything before touching the current world.
+        let level = Level::parse(source)?;
+
+        let mut world = GameContainer::empty_world();
+        // Levels keep the seed of the world they replace.
+        world.insert(GameRng::new(self.seed()));
+        world.insert(ReplayRecorder {
+            replay: Replay::new(self.seed(), Some(source.to_string())),
+        });
+
+        // Build every entity of the level.
+        let mut ls = LevelLoadSystem { level: &level };
+        ls.run_now(&world);
+
+        // Apply the changes done with LazyUpdate to our world.
+        world.maintain();
+
+        // Keep the level around for the systems and for snapshots.
+        world.insert(LoadedLevel { source: source.to_string(), level });
+
+        self.world = world;
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
 #[derive(Default, Debug)]
 pub struct GameObjectContainer {
     // Every time log_entities is ran, an updated instance of this is sent back.
-    data: [Option<GameObject>; 32],
-    length: usize,
+    data: Vec<GameObject>,
 }
 
-#[wasm_bindgen]
+// Floats per object in GameObjectContainer::transforms.
+const TRANSFORM_STRIDE: usize = 6;
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
 impl GameObjectContainer {
     pub fn len(&self) -> u32 {
         // Get length of container for looping
-        self.length as u32
+        self.data.len() as u32
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
     pub fn get(&self, idx: usize) -> GameObject {
         // Get GameObject from list.
-        self.data[idx].unwrap()
+        self.data[idx]
     }
     pub fn push(&mut self, object: GameObject) {
-        self.data[self.length] = Some(object);
-        self.length += 1;
+        self.data.push(object);
         //log(&format!("{:#?}", self.data));
     }
+    pub fn ids(&self) -> Vec<u32> {
+        // The ids of every object, in the same order as transforms().
+        self.data.iter().map(|object| object.id).collect()
+    }
+    pub fn transforms(&self) -> Vec<f32> {
+        // Every position and rotation in one array, so the frontend
+        // doesn't have to ask for them object by object.
+        // Layout per object: [pos x, pos y, pos z, rot x, rot y, rot z].
+        let mut transforms = Vec::with_capacity(self.data.len() * TRANSFORM_STRIDE);
+        for object in self.data.iter() {
+            transforms.extend_from_slice(&object.pos);
+            transforms.extend_from_slice(&object.rot);
+        }
+        transforms
+    }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
 #[derive(Clone, Copy, Debug)]
 // This is what gets sent in an array to the Javascript frontend.
 pub struct GameObject {
@@ -182,12 +911,11 @@ pub struct GameObject {
 }
 
 // Implement getter fuctions for the frontend.
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
 impl GameObject {
-    pub fn name(&self) -> JsString {
+    pub fn name(&self) -> String {
         // transform chars into string
-        let string: String = self.name.iter().collect();
-        string.into()
+        self.name.iter().collect()
     }
     pub fn id(&self) -> u32 {
         self.id
@@ -195,45 +923,222 @@ impl GameObject {
     pub fn physics_type(&self) -> PhysicsType {
         self.physics
     }
-    pub fn pos(&self) -> Array {
-        let pos_array = Array::new_with_length(3);
+    pub fn pos(&self) -> Vec<f32> {
         // The array shouldn't have more than 3 items.
-        for index in 0..=2 {
-            pos_array.set(index as u32, self.pos[index].into());
-        };
-        pos_array
+        self.pos.to_vec()
     }
-    pub fn rot(&self) -> Array {
-        let rot_array = Array::new_with_length(3);
+    pub fn rot(&self) -> Vec<f32> {
         // The array shouldn't have more than 3 items.
-        for index in 0..=2 {
-            rot_array.set(index as u32, self.rot[index].into());
-        };
-        rot_array
+        self.rot.to_vec()
     }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Default, Debug)]
+// Engine data of the player's car, sent to the frontend every frame.
+pub struct CarState {
+    rpm: f32,
+    gear: i32,
+    throttle: f32,
+    speed: f32,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl CarState {
+    pub fn rpm(&self) -> f32 {
+        self.rpm
+    }
+    pub fn gear(&self) -> i32 {
+        // -1 is reverse, 0 is neutral.
+        self.gear
+    }
+    pub fn throttle(&self) -> f32 {
+        self.throttle
+    }
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RacePhase {
+    Idle,       // Free driving, no race going on.
+    Countdown,  // Waiting for the green light.
+    Racing,
+    Finished,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Debug)]
+// The player's race, for the HUD.
+pub struct RaceState {
+    phase: RacePhase,
+    countdown: f32,
+    time: f32,
+    total_laps: u32,
+    checkpoint_count: u32,
+    racers: u32,
+    progress: RaceProgress,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl RaceState {
+    pub fn phase(&self) -> RacePhase {
+        self.phase
+    }
+    pub fn countdown(&self) -> f32 {
+        // Seconds until the green light.
+        self.countdown
+    }
+    pub fn time(&self) -> f32 {
+        // Seconds since the green light.
+        self.time
+    }
+    pub fn lap(&self) -> u32 {
+        self.progress.lap.min(self.total_laps)
+    }
+    pub fn total_laps(&self) -> u32 {
+        self.total_laps
+    }
+    pub fn position(&self) -> u32 {
+        self.progress.position
+    }
+    pub fn racers(&self) -> u32 {
+        self.racers
+    }
+    pub fn next_checkpoint(&self) -> u32 {
+        self.progress.next_checkpoint
+    }
+    pub fn checkpoint_count(&self) -> u32 {
+        self.checkpoint_count
+    }
+    pub fn current_lap_time(&self) -> f32 {
+        self.progress.current_lap
+    }
+    pub fn last_lap_time(&self) -> Option<f32> {
+        self.progress.last_lap
+    }
+    pub fn best_lap_time(&self) -> Option<f32> {
+        self.progress.best_lap
+    }
+    pub fn finish_time(&self) -> Option<f32> {
+        self.progress.finish_time
+    }
+    pub fn splits(&self) -> Vec<f32> {
+        // Times into the current lap at each checkpoint passed.
+        self.progress.splits.clone()
+    }
+    pub fn best_splits(&self) -> Vec<f32> {
+        self.progress.best_splits.clone()
+    }
+    pub fn wrong_way(&self) -> bool {
+        self.progress.wrong_way
+    }
+    pub fn missed_checkpoint(&self) -> bool {
+        self.progress.missed_checkpoint
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameEventKind {
+    ContactStarted,
+    ContactStopped,
+    IntersectionStarted,
+    IntersectionStopped,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+// Something that happened between two entities during a physics step.
+pub struct GameEvent {
+    kind: GameEventKind,
+    entity_a: u32,
+    entity_b: u32,
+    // How hard they hit, zero for intersections and stopped contacts.
+    impulse: f32,
+    point: [f32; 3],
+    tick: u64,
+}
+
+impl GameEvent {
+    pub fn new(kind: GameEventKind, entity_a: u32, entity_b: u32, impulse: f32, point: [f32; 3], tick: u64) -> GameEvent {
+        GameEvent { kind, entity_a, entity_b, impulse, point, tick }
+    }
+    pub fn involves(&self, entity: u32) -> bool {
+        self.entity_a == entity || self.entity_b == entity
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl GameEvent {
+    pub fn kind(&self) -> GameEventKind {
+        self.kind
+    }
+    pub fn entity_a(&self) -> u32 {
+        self.entity_a
+    }
+    pub fn entity_b(&self) -> u32 {
+        self.entity_b
+    }
+    pub fn impulse(&self) -> f32 {
+        self.impulse
+    }
+    pub fn point(&self) -> Vec<f32> {
+        self.point.to_vec()
+    }
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Default, Debug)]
+pub struct GameEventContainer {
+    data: Vec<GameEvent>,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl GameEventContainer {
+    pub fn len(&self) -> u32 {
+        self.data.len() as u32
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    pub fn get(&self, idx: usize) -> GameEvent {
+        self.data[idx]
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
 // Used for the index of GameKeysContainer
 pub enum GameKeys {
     Acceleration = 0,
     Brakes = 1,
     Left = 2,
     Right = 3,
+    GearUp = 4,
+    GearDown = 5,
+    Handbrake = 6,
+    Boost = 7,
+    Reset = 8,
+    Camera = 9,
 }
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
 #[derive(Clone, Copy, Default, Debug)]
 pub struct GameKeysContainer {
-    keys: [bool; 4],
+    keys: [bool; 10],
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen)]
 // This is a container that the Javascript frontend sends when running the systems.
 impl GameKeysContainer {
     pub fn new() -> GameKeysContainer {
         //GameKeysContainer::default()
         GameKeysContainer {
-            keys: [false, false, false, false]
+            keys: [false; 10]
         }
     }
     pub fn set(&mut self, idx: usize, value: bool) {
@@ -248,6 +1153,135 @@ impl GameKeysContainer {
     }
 }
 
+impl GameKeysContainer {
+    pub fn to_input(&self) -> GameInput {
+        // Pressed keys are full pedal, both steering keys cancel out.
+        let axis = |key: GameKeys| if self.get(key as usize) { 1.0 } else { 0.0 };
+        GameInput {
+            throttle: axis(GameKeys::Acceleration),
+            brake: axis(GameKeys::Brakes),
+            steering: axis(GameKeys::Left) - axis(GameKeys::Right),
+            handbrake: self.get(GameKeys::Handbrake as usize),
+            boost: self.get(GameKeys::Boost as usize),
+            reset: self.get(GameKeys::Reset as usize),
+            camera: self.get(GameKeys::Camera as usize),
+            gear_up: self.get(GameKeys::GearUp as usize),
+            gear_down: self.get(GameKeys::GearDown as usize),
+        }
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Default, Debug)]
+// The input of every local player, indexed by player slot.
+pub struct GameInputSet {
+    inputs: Vec<GameInput>,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl GameInputSet {
+    pub fn new() -> GameInputSet {
+        GameInputSet::default()
+    }
+    pub fn set(&mut self, slot: usize, input: &GameInput) {
+        // Slots in between stay without input, ones past the last player are ignored.
+        if slot >= MAX_PLAYERS as usize {
+            return;
+        }
+        if self.inputs.len() <= slot {
+            self.inputs.resize(slot + 1, GameInput::default());
+        }
+        self.inputs[slot] = *input;
+    }
+    pub fn get(&self, slot: usize) -> GameInput {
+        self.inputs.get(slot).copied().unwrap_or_default()
+    }
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize, Deserialize)]
+// Analog version of GameKeysContainer, for gamepads and touch controls.
+pub struct GameInput {
+    throttle: f32,  // 0.0 to 1.0
+    brake: f32,     // 0.0 to 1.0
+    steering: f32,  // -1.0 (right) to 1.0 (left)
+    handbrake: bool,
+    boost: bool,
+    reset: bool,
+    camera: bool,
+    gear_up: bool,
+    gear_down: bool,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl GameInput {
+    pub fn new() -> GameInput {
+        GameInput::default()
+    }
+    pub fn set_throttle(&mut self, value: f32) {
+        self.throttle = value.clamp(0.0, 1.0);
+    }
+    pub fn set_brake(&mut self, value: f32) {
+        self.brake = value.clamp(0.0, 1.0);
+    }
+    pub fn set_steering(&mut self, value: f32) {
+        self.steering = value.clamp(-1.0, 1.0);
+    }
+    pub fn set_handbrake(&mut self, value: bool) {
+        self.handbrake = value;
+    }
+    pub fn set_boost(&mut self, value: bool) {
+        self.boost = value;
+    }
+    pub fn set_reset(&mut self, value: bool) {
+        self.reset = value;
+    }
+    pub fn set_camera(&mut self, value: bool) {
+        self.camera = value;
+    }
+    pub fn set_gear_up(&mut self, value: bool) {
+        self.gear_up = value;
+    }
+    pub fn set_gear_down(&mut self, value: bool) {
+        self.gear_down = value;
+    }
+
+    pub fn throttle(&self) -> f32 {
+        self.throttle
+    }
+    pub fn brake(&self) -> f32 {
+        self.brake
+    }
+    pub fn steering(&self) -> f32 {
+        self.steering
+    }
+    pub fn handbrake(&self) -> bool {
+        self.handbrake
+    }
+    pub fn boost(&self) -> bool {
+        self.boost
+    }
+    pub fn reset(&self) -> bool {
+        self.reset
+    }
+    pub fn camera(&self) -> bool {
+        self.camera
+    }
+    pub fn gear_up(&self) -> bool {
+        self.gear_up
+    }
+    pub fn gear_down(&self) -> bool {
+        self.gear_down
+    }
+}
+
+#[cfg(feature = "web")]
 #[wasm_bindgen]
 // Access to basic Javascript commands.
 extern {
@@ -256,3 +1290,9 @@ extern {
 
     fn alert(s: &str);
 }
+
+#[cfg(not(feature = "web"))]
+// Without a browser, log to the terminal.
+fn log(s: &str) {
+    eprintln!("{}", s);
+}
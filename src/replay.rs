@@ -0,0 +1,546 @@
+use std::fmt;
+
+use nalgebra::DMatrix;
+use serde::{Serialize, Deserialize};
+
+use crate::{GameInput, components::{GearboxMode, SurfaceMaterial}};
+
+// Everything needed to simulate a session again: the seed, the level,
+// the input of every fixed tick and the calls that changed the game.
+//
+// Binary layout, little endian:
+//   "RPLY"              magic
+//   u16                 format version
+//   u64                 seed
+//   u32 + bytes         level source as UTF-8, empty for the random world
+//   u64                 ticks simulated
+//   u64                 state hash after the last tick
+//   u32                 input run count
+//   per run:
+//     u32               ticks the inputs were held
+//     u8                player count
+//     per player:
+//       f32 x3          throttle, brake, steering
+//       u8              buttons, see the bits below
+//   u32                 command count
+//   per command:
+//     u64               tick it was applied before
+//     u8                command type, then its payload
+//
+// Maps (command 3) are stored whole:
+//   u32 x2              rows and columns
+//   f32 per height      column by column
+//   f32 x3              scale
+//   u8                  1 when a material grid follows, else 0
+//   u8 per material     column by column, see SurfaceMaterial::from_index
+
+const MAGIC: &[u8; 4] = b"RPLY";
+const VERSION: u16 = 1;
+
+// Bits of the button byte.
+const HANDBRAKE: u8 = 1 << 0;
+const BOOST: u8 = 1 << 1;
+const RESET: u8 = 1 << 2;
+const CAMERA: u8 = 1 << 3;
+const GEAR_UP: u8 = 1 << 4;
+const GEAR_DOWN: u8 = 1 << 5;
+
+// Calls to GameContainer that change the simulation besides the input.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplayCommand {
+    StartRace(u32),
+    SetGearboxMode(GearboxMode),
+    SetTireParams(String),
+    // From create_map, create_map_from_image and generate_map.
+    SetMap {
+        heights: DMatrix<f32>,
+        scale: [f32; 3],
+        materials: Option<DMatrix<SurfaceMaterial>>,
+    },
+    // From add_player.
+    AddPlayer,
+}
+
+// The same inputs held for a number of ticks, one per player slot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputRun {
+    pub ticks: u32,
+    pub inputs: Vec<GameInput>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Replay {
+    pub seed: u64,
+    pub level: Option<String>,
+    pub ticks: u64,
+    pub state_hash: u64,
+    pub inputs: Vec<InputRun>,
+    pub commands: Vec<(u64, ReplayCommand)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayError {
+    // Doesn't start with the magic bytes.
+    NotAReplay,
+    UnsupportedVersion(u16),
+    // Shorter than its contents say.
+    Truncated,
+    // Parsed, but describes something we can't simulate.
+    Invalid(String),
+    // The simulation ended somewhere else than where it was recorded.
+    Desync { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::NotAReplay => write!(f, "Not a replay file"),
+            ReplayError::UnsupportedVersion(version) => write!(f, "Unsupported replay version {}", version),
+            ReplayError::Truncated => write!(f, "Replay data is truncated"),
+            ReplayError::Invalid(message) => write!(f, "Invalid replay: {}", message),
+            ReplayError::Desync { expected, actual } => {
+                write!(f, "Replay desynced: expected state {:016x}, got {:016x}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Replay {
+    pub fn new(seed: u64, level: Option<String>) -> Replay {
+        Replay {
+            seed,
+            level,
+            ..Replay::default()
+        }
+    }
+
+    pub fn push_inputs(&mut self, inputs: &[GameInput]) {
+        // Inputs rarely change between ticks, so hold the last ones longer.
+        self.ticks += 1;
+        if let Some(run) = self.inputs.last_mut() {
+            if run.inputs == inputs && run.ticks < u32::MAX {
+                run.ticks += 1;
+                return;
+            }
+        }
+        self.inputs.push(InputRun { ticks: 1, inputs: inputs.to_vec() });
+    }
+
+    pub fn push_command(&mut self, tick: u64, command: ReplayCommand) {
+        self.commands.push((tick, command));
+    }
+
+    pub fn tick_inputs(&self) -> impl Iterator<Item = &[GameInput]> + '_ {
+        // The inputs of every tick, in order.
+        self.inputs.iter().flat_map(|run| std::iter::repeat_n(&run.inputs[..], run.ticks as usize))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ReplayError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        write_string(&mut bytes, self.level.as_deref().unwrap_or(""));
+        bytes.extend_from_slice(&self.ticks.to_le_bytes());
+        bytes.extend_from_slice(&self.state_hash.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for run in self.inputs.iter() {
+            bytes.extend_from_slice(&run.ticks.to_le_bytes());
+            if run.inputs.len() > u8::MAX as usize {
+                return Err(ReplayError::Invalid(format!("{} players in one tick", run.inputs.len())));
+            }
+            bytes.push(run.inputs.len() as u8);
+            for input in run.inputs.iter() {
+                write_input(&mut bytes, input);
+            }
+        }
+
+        bytes.extend_from_slice(&(self.commands.len() as u32).to_le_bytes());
+        for (tick, command) in self.commands.iter() {
+            bytes.extend_from_slice(&tick.to_le_bytes());
+            match command {
+                ReplayCommand::StartRace(laps) => {
+                    bytes.push(0);
+                    bytes.extend_from_slice(&laps.to_le_bytes());
+                }
+                ReplayCommand::SetGearboxMode(mode) => {
+                    bytes.push(1);
+                    bytes.push(match mode {
+                        GearboxMode::Automatic => 0,
+                        GearboxMode::Manual => 1,
+                    });
+                }
+                ReplayCommand::SetTireParams(json) => {
+                    bytes.push(2);
+                    write_string(&mut bytes, json);
+                }
+                ReplayCommand::SetMap { heights, scale, materials } => {
+                    bytes.push(3);
+                    bytes.extend_from_slice(&(heights.nrows() as u32).to_le_bytes());
+                    bytes.extend_from_slice(&(heights.ncols() as u32).to_le_bytes());
+                    for value in heights.iter().chain(scale.iter()) {
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                    match materials {
+                        Some(materials) => {
+                            if materials.shape() != heights.shape() {
+                                return Err(ReplayError::Invalid("the materials don't match the map".to_string()));
+                            }
+                            bytes.push(1);
+                            bytes.extend(materials.iter().map(|material| *material as u8));
+                        }
+                        None => bytes.push(0),
+                    }
+                }
+                ReplayCommand::AddPlayer => bytes.push(4),
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Replay, ReplayError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(ReplayError::NotAReplay);
+        }
+        let mut reader = ByteReader { bytes, offset: MAGIC.len() };
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        let seed = reader.u64()?;
+        let level = reader.string()?;
+        let level = if level.is_empty() { None } else { Some(level) };
+        let ticks = reader.u64()?;
+        let state_hash = reader.u64()?;
+
+        let run_count = reader.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..run_count {
+            let run_ticks = reader.u32()?;
+            let players = reader.u8()?;
+            let mut run_inputs = Vec::with_capacity(players as usize);
+            for _ in 0..players {
+                run_inputs.push(reader.input()?);
+            }
+            inputs.push(InputRun { ticks: run_ticks, inputs: run_inputs });
+        }
+
+        let command_count = reader.u32()?;
+        let mut commands = Vec::new();
+        for _ in 0..command_count {
+            let tick = reader.u64()?;
+            let command = match reader.u8()? {
+                0 => ReplayCommand::StartRace(reader.u32()?),
+                1 => match reader.u8()? {
+                    0 => ReplayCommand::SetGearboxMode(GearboxMode::Automatic),
+                    1 => ReplayCommand::SetGearboxMode(GearboxMode::Manual),
+                    mode => return Err(ReplayError::Invalid(format!("unknown gearbox mode {}", mode))),
+                },
+                2 => ReplayCommand::SetTireParams(reader.string()?),
+                3 => reader.map()?,
+                4 => ReplayCommand::AddPlayer,
+                kind => return Err(ReplayError::Invalid(format!("unknown command {}", kind))),
+            };
+            commands.push((tick, command));
+        }
+
+        let input_ticks: u64 = inputs.iter().map(|run| run.ticks as u64).sum();
+        if input_ticks != ticks {
+            return Err(ReplayError::Invalid(format!("{} ticks of input for {} ticks", input_ticks, ticks)));
+        }
+        if commands.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+            return Err(ReplayError::Invalid("commands are out of order".to_string()));
+        }
+
+        Ok(Replay {
+            seed,
+            level,
+            ticks,
+            state_hash,
+            inputs,
+            commands,
+        })
+    }
+}
+
+fn write_input(bytes: &mut Vec<u8>, input: &GameInput) {
+    bytes.extend_from_slice(&input.throttle().to_le_bytes());
+    bytes.extend_from_slice(&input.brake().to_le_bytes());
+    bytes.extend_from_slice(&input.steering().to_le_bytes());
+
+    let buttons = [
+        (input.handbrake(), HANDBRAKE),
+        (input.boost(), BOOST),
+        (input.reset(), RESET),
+        (input.camera(), CAMERA),
+        (input.gear_up(), GEAR_UP),
+        (input.gear_down(), GEAR_DOWN),
+    ];
+    let flags = buttons.iter()
+        .filter(|(pressed, _)| *pressed)
+        .fold(0, |flags, (_, bit)| flags | bit);
+    bytes.push(flags);
+}
+
+fn write_string(bytes: &mut Vec<u8>, text: &str) {
+    bytes.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+// Reads values one after the other, failing at the end of the data.
+struct ByteReader<'b> {
+    bytes: &'b [u8],
+    offset: usize,
+}
+
+impl<'b> ByteReader<'b> {
+    fn take(&mut self, length: usize) -> Result<&'b [u8], ReplayError> {
+        let end = self.offset.checked_add(length).ok_or(ReplayError::Truncated)?;
+        let slice = self.bytes.get(self.offset..end).ok_or(ReplayError::Truncated)?;
+        self.offset = end;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, ReplayError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, ReplayError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    fn u32(&mut self) -> Result<u32, ReplayError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    fn u64(&mut self) -> Result<u64, ReplayError> {
+        let bytes = self.take(8)?;
+        let mut array = [0; 8];
+        array.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(array))
+    }
+    fn f32(&mut self) -> Result<f32, ReplayError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+    fn map(&mut self) -> Result<ReplayCommand, ReplayError> {
+        let rows = self.u32()? as usize;
+        let columns = self.u32()? as usize;
+        if rows < 2 || columns < 2 {
+            return Err(ReplayError::Invalid(format!("a {}x{} map", rows, columns)));
+        }
+        // Check the size against the data before allocating for it.
+        let cells = rows.checked_mul(columns).ok_or(ReplayError::Truncated)?;
+        if cells > (self.bytes.len() - self.offset) / 4 {
+            return Err(ReplayError::Truncated);
+        }
+
+        let mut heights = Vec::with_capacity(cells);
+        for _ in 0..cells {
+            heights.push(self.f32()?);
+        }
+        let heights = DMatrix::from_vec(rows, columns, heights);
+        let scale = [self.f32()?, self.f32()?, self.f32()?];
+
+        let materials = match self.u8()? {
+            0 => None,
+            _ => {
+                let mut materials = Vec::with_capacity(cells);
+                for &index in self.take(cells)? {
+                    let material = SurfaceMaterial::from_index(index as u32)
+                        .ok_or_else(|| ReplayError::Invalid(format!("unknown material {}", index)))?;
+                    materials.push(material);
+                }
+                Some(DMatrix::from_vec(rows, columns, materials))
+            }
+        };
+        Ok(ReplayCommand::SetMap { heights, scale, materials })
+    }
+    fn input(&mut self) -> Result<GameInput, ReplayError> {
+        let mut input = GameInput::new();
+        input.set_throttle(self.f32()?);
+        input.set_brake(self.f32()?);
+        input.set_steering(self.f32()?);
+        let flags = self.u8()?;
+        input.set_handbrake(flags & HANDBRAKE != 0);
+        input.set_boost(flags & BOOST != 0);
+        input.set_reset(flags & RESET != 0);
+        input.set_camera(flags & CAMERA != 0);
+        input.set_gear_up(flags & GEAR_UP != 0);
+        input.set_gear_down(flags & GEAR_DOWN != 0);
+        Ok(input)
+    }
+    fn string(&mut self) -> Result<String, ReplayError> {
+        let length = self.u32()? as usize;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ReplayError::Invalid("text is not UTF-8".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{vector, DMatrix};
+
+    use super::*;
+    use crate::{GameContainer, GameInputSet, terrain::TerrainParams};
+
+    const RAMPS: &str = include_str!("../www/resources/levels/ramps.ron");
+
+    fn input(throttle: f32, steering: f32, handbrake: bool) -> GameInput {
+        let mut input = GameInput::new();
+        input.set_throttle(throttle);
+        input.set_steering(steering);
+        input.set_handbrake(handbrake);
+        input
+    }
+
+    fn drive(game: &mut GameContainer, ticks: usize, input: GameInput) {
+        for _ in 0..ticks {
+            game.step_with_input(&input);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut replay = Replay::new(7, Some(RAMPS.to_string()));
+        replay.push_inputs(&[input(1.0, 0.0, false)]);
+        replay.push_inputs(&[input(1.0, 0.0, false)]);
+        replay.push_inputs(&[input(0.5, -0.25, true), input(1.0, 0.5, false)]);
+        replay.push_command(0, ReplayCommand::StartRace(3));
+        replay.push_command(1, ReplayCommand::SetGearboxMode(GearboxMode::Manual));
+        replay.push_command(2, ReplayCommand::SetMap {
+            heights: DMatrix::from_fn(2, 3, |row, column| (row * 3 + column) as f32),
+            scale: [10.0, 1.0, 20.0],
+            materials: Some(DMatrix::from_fn(2, 3, |row, _column| {
+                if row == 0 { SurfaceMaterial::Grass } else { SurfaceMaterial::Sand }
+            })),
+        });
+        replay.push_command(2, ReplayCommand::AddPlayer);
+        replay.state_hash = 0x1234;
+
+        let decoded = Replay::from_bytes(&replay.to_bytes().unwrap()).unwrap();
+        assert_eq!((decoded.seed, decoded.ticks, decoded.state_hash), (7, 3, 0x1234));
+        assert_eq!(decoded.level, replay.level);
+        assert_eq!(decoded.commands, replay.commands);
+        // Equal inputs are held, not repeated.
+        assert_eq!(decoded.inputs.len(), 2);
+        assert!(decoded.tick_inputs().eq(replay.tick_inputs()));
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let bytes = Replay::new(1, None).to_bytes().unwrap();
+        assert_eq!(Replay::from_bytes(b"GHST").unwrap_err(), ReplayError::NotAReplay);
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(Replay::from_bytes(&newer).unwrap_err(), ReplayError::UnsupportedVersion(VERSION + 1));
+
+        for length in MAGIC.len()..bytes.len() {
+            assert_eq!(Replay::from_bytes(&bytes[..length]).unwrap_err(), ReplayError::Truncated);
+        }
+
+        // A map claiming far more heights than the file holds.
+        let mut replay = Replay::new(1, None);
+        replay.push_command(0, ReplayCommand::SetMap {
+            heights: DMatrix::zeros(2, 2),
+            scale: [1.0; 3],
+            materials: None,
+        });
+        let mut huge = replay.to_bytes().unwrap();
+        let rows = huge.len() - 1 - 4 * 7 - 8;
+        huge[rows..rows + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Replay::from_bytes(&huge).unwrap_err(), ReplayError::Truncated);
+    }
+
+    #[test]
+    fn replays_end_in_the_same_state() {
+        let mut game = GameContainer::create_with_seed(3);
+        game.try_load_level(RAMPS).unwrap();
+        drive(&mut game, 30, input(0.0, 0.0, false));
+        game.start_race(2);
+        game.set_gearbox_mode(GearboxMode::Manual);
+        drive(&mut game, 120, input(1.0, 0.3, false));
+        drive(&mut game, 60, input(0.6, -0.5, true));
+
+        let bytes = game.export_replay();
+        let mut replayed = GameContainer::create_with_seed(0);
+        replayed.try_play_replay(&bytes).unwrap();
+        assert_eq!(replayed.tick(), game.tick());
+        assert_eq!(replayed.state_hash(), game.state_hash());
+
+        // Without the race and the manual gearbox the car ends elsewhere.
+        let mut replay = Replay::from_bytes(&bytes).unwrap();
+        replay.commands.clear();
+        let error = replayed.try_play_replay(&replay.to_bytes().unwrap()).unwrap_err();
+        assert!(matches!(error, ReplayError::Desync { .. }));
+    }
+
+    #[test]
+    fn replays_bring_back_maps() {
+        let mut game = GameContainer::create_with_seed(5);
+        let ice = DMatrix::from_element(4, 4, SurfaceMaterial::Ice);
+        game.replace_map(DMatrix::from_element(4, 4, 0.0), vector![200.0, 1.0, 200.0], Some(ice));
+        drive(&mut game, 90, input(1.0, 0.0, false));
+
+        let bytes = game.export_replay();
+        let mut replayed = GameContainer::create_with_seed(0);
+        replayed.try_play_replay(&bytes).unwrap();
+        assert_eq!(replayed.state_hash(), game.state_hash());
+
+        // The car grips differently on asphalt.
+        let mut replay = Replay::from_bytes(&bytes).unwrap();
+        if let Some((_, ReplayCommand::SetMap { materials, .. })) = replay.commands.first_mut() {
+            *materials = None;
+        }
+        let error = replayed.try_play_replay(&replay.to_bytes().unwrap()).unwrap_err();
+        assert!(matches!(error, ReplayError::Desync { .. }));
+    }
+
+    #[test]
+    fn replays_bring_back_generated_maps() {
+        let mut game = GameContainer::create_with_seed(11);
+        game.try_generate_map(&TerrainParams { size: 33, ..TerrainParams::default() }).unwrap();
+        drive(&mut game, 90, input(1.0, 0.3, false));
+
+        let mut replayed = GameContainer::create_with_seed(0);
+        replayed.try_play_replay(&game.export_replay()).unwrap();
+        assert_eq!(replayed.map_heights(), game.map_heights());
+        assert_eq!(replayed.state_hash(), game.state_hash());
+    }
+
+    #[test]
+    fn replays_bring_back_image_maps() {
+        let mut game = GameContainer::create_with_seed(13);
+        let image = include_bytes!("../tests/fixtures/heightmap_p5.pgm");
+        game.try_create_map_from_image(image, [200.0, 5.0, 100.0]).unwrap();
+        drive(&mut game, 90, input(1.0, -0.3, false));
+
+        let mut replayed = GameContainer::create_with_seed(0);
+        replayed.try_play_replay(&game.export_replay()).unwrap();
+        assert_eq!(replayed.map_heights(), game.map_heights());
+        assert_eq!(replayed.map_scale(), game.map_scale());
+        assert_eq!(replayed.state_hash(), game.state_hash());
+    }
+
+    #[test]
+    fn replays_bring_back_extra_players() {
+        let mut game = GameContainer::create_with_seed(9);
+        game.try_load_level(RAMPS).unwrap();
+        assert_eq!(game.add_player(), Some(1));
+
+        let mut inputs = GameInputSet::new();
+        inputs.set(0, &input(1.0, 0.2, false));
+        inputs.set(1, &input(0.8, -0.4, false));
+        for _ in 0..120 {
+            game.step_multi(&inputs);
+        }
+
+        let mut replayed = GameContainer::create_with_seed(0);
+        replayed.try_play_replay(&game.export_replay()).unwrap();
+        assert_eq!(replayed.player_count(), 2);
+        assert_eq!(replayed.state_hash(), game.state_hash());
+    }
+}
@@ -1,16 +1,28 @@
+use std::ops::Range;
+
 use nalgebra::vector;
 use parry3d::math::{Vector, Real};
-use rapier3d::prelude::{PhysicsPipeline, RigidBodySet, ColliderSet, IntegrationParameters, IslandManager, BroadPhase, NarrowPhase, JointSet, CCDSolver, PhysicsHooks, EventHandler};
+use rapier3d::prelude::{PhysicsPipeline, RigidBodySet, ColliderSet, IntegrationParameters, IslandManager, BroadPhase, NarrowPhase, JointSet, CCDSolver, QueryPipeline, ChannelEventCollector, ContactEvent, IntersectionEvent};
+use rapier3d::crossbeam::channel::{unbounded, Receiver};
+use serde::{Serialize, Deserialize};
 use specs::World;
 
-use crate::GameKeysContainer;
+use crate::{GameInput, GameEvent, RacePhase, level::Level, components::TriggerKind, ghost::{Ghost, GhostFrame}, replay::Replay};
 
 pub fn insert_resources(world: &mut World) {
     // Insert the physics resources to the world.
     world.insert(RigidBodyContainer::default());
     world.insert(ColliderContainer::default());
     world.insert(PhysicsResource::default());
-    world.insert(GameKeysContainer::default());
+    world.insert(PlayerInputs::default());
+    world.insert(SimulationClock::default());
+    world.insert(LoadedLevel::default());
+    world.insert(GameRng::default());
+    world.insert(GameEvents::default());
+    world.insert(TriggerEvents::default());
+    world.insert(RaceSession::default());
+    world.insert(GhostRecorder::default());
+    world.insert(ReplayRecorder::default());
 }
 
 // Custom Structs to hold RigidBodySet & ColliderSet Resources;
@@ -28,6 +40,188 @@ impl Default for ColliderContainer {
         ColliderContainer(rb)
     }
 }
+// The input of every local player, by player slot.
+#[derive(Default)]
+pub struct PlayerInputs {
+    pub slots: Vec<GameInput>,
+}
+impl PlayerInputs {
+    pub fn get(&self, slot: u32) -> GameInput {
+        // Players without input this step don't press anything.
+        self.slots.get(slot as usize).copied().unwrap_or_default()
+    }
+}
+
+// Most events kept for Javascript before the oldest get dropped.
+const MAX_EVENT_OUTBOX: usize = 1024;
+
+// Collisions and other things that happened during the game.
+#[derive(Default)]
+pub struct GameEvents {
+    // What happened during the current tick, for other systems.
+    pub current: Vec<GameEvent>,
+    // Everything since Javascript last asked for the events.
+    pub outbox: Vec<GameEvent>,
+}
+impl GameEvents {
+    pub fn start_tick(&mut self) {
+        self.current.clear();
+    }
+    pub fn push(&mut self, event: GameEvent) {
+        self.current.push(event);
+        self.outbox.push(event);
+        if self.outbox.len() > MAX_EVENT_OUTBOX {
+            let overflow = self.outbox.len() - MAX_EVENT_OUTBOX;
+            self.outbox.drain(..overflow);
+        }
+    }
+}
+
+// An entity entering or leaving a trigger volume.
+#[derive(Clone, Copy, Debug)]
+pub struct TriggerEvent {
+    pub trigger: u32,
+    pub kind: TriggerKind,
+    pub entity: u32,
+    // False when the entity left the trigger.
+    pub entered: bool,
+}
+
+// Trigger events of the current tick, filled by the TriggerSystem.
+#[derive(Default)]
+pub struct TriggerEvents {
+    pub current: Vec<TriggerEvent>,
+}
+impl TriggerEvents {
+    pub fn entered(&self, entity: u32) -> impl Iterator<Item = &TriggerEvent> {
+        // Every trigger this entity drove into during the tick.
+        self.current.iter().filter(move |event| event.entered && event.entity == entity)
+    }
+}
+
+// The race everyone is driving in. Without a race the cars drive freely.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RaceSession {
+    pub phase: RacePhase,
+    pub laps: u32,
+    // Seconds of countdown left before the green light.
+    pub countdown: f32,
+    // Seconds since the green light.
+    pub time: f32,
+    pub checkpoint_count: u32,
+    // Without a finish line, checkpoint 0 is where laps start and end.
+    pub has_finish_line: bool,
+}
+impl Default for RaceSession {
+    fn default() -> Self {
+        RaceSession {
+            phase: RacePhase::Idle,
+            laps: 3,
+            countdown: 0.0,
+            time: 0.0,
+            checkpoint_count: 0,
+            has_finish_line: false,
+        }
+    }
+}
+impl RaceSession {
+    pub fn inputs_locked(&self) -> bool {
+        // Nobody moves until the green light.
+        self.phase == RacePhase::Countdown
+    }
+}
+
+// The level the world was built from. Empty when using the random world.
+#[derive(Default)]
+pub struct LoadedLevel {
+    // Saved in snapshots, to build the level again on load.
+    pub source: String,
+    pub level: Level,
+}
+
+// Records the player's current lap and keeps the best one as a ghost.
+#[derive(Default, Serialize, Deserialize)]
+pub struct GhostRecorder {
+    // The lap being recorded, 0 when not recording.
+    pub lap: u32,
+    pub recording: Vec<GhostFrame>,
+    pub best: Option<Ghost>,
+}
+impl GhostRecorder {
+    pub fn restart(&mut self) {
+        // Drop the unfinished lap, but keep the best one.
+        self.lap = 0;
+        self.recording.clear();
+    }
+}
+
+// Every input and command since the world was created, to replay it later.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ReplayRecorder {
+    pub replay: Replay,
+}
+
+// Seeded random numbers (SplitMix64), so the same seed
+// always generates the same world on every platform.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameRng {
+    pub seed: u64,
+    state: u64,
+}
+impl Default for GameRng {
+    fn default() -> Self {
+        GameRng::new(0)
+    }
+}
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng { seed, state: seed }
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+    pub fn next_f32(&mut self) -> f32 {
+        // Use the top 24 bits, that's all the precision a f32 has.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+    pub fn range(&mut self, range: Range<f32>) -> f32 {
+        range.start + self.next_f32() * (range.end - range.start)
+    }
+    pub fn sign(&mut self) -> f32 {
+        if self.next_f32() > 0.5 { 1.0 } else { -1.0 }
+    }
+}
+
+// Keeps the simulation running at the same speed on every monitor.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SimulationClock {
+    // Seconds simulated by every physics step.
+    pub fixed_dt: f32,
+    // Wall time that still has to be simulated.
+    pub accumulator: f32,
+    // Most steps we run in a single frame, so a slow frame can't snowball.
+    pub max_substeps: u32,
+    // How far we are between the last two steps (0.0 to 1.0), for rendering.
+    pub alpha: f32,
+    // Steps simulated since the game started.
+    pub tick: u64,
+}
+impl Default for SimulationClock {
+    fn default() -> Self {
+        SimulationClock {
+            fixed_dt: 1.0 / 60.0,
+            accumulator: 0.0,
+            max_substeps: 5,
+            alpha: 0.0,
+            tick: 0,
+        }
+    }
+}
+
 // We need to store these or physics step won't take place.
 pub struct PhysicsResource {
     pub physics_pipeline: PhysicsPipeline,
@@ -38,8 +232,13 @@ pub struct PhysicsResource {
     pub narrow_phase: NarrowPhase,
     pub joint_set: JointSet,
     pub ccd_solver: CCDSolver,
+    // Used for raycasts (like the wheels) between steps.
+    pub query_pipeline: QueryPipeline,
     pub physics_hooks: (),
-    pub event_handler: (),
+    // Rapier sends the collision events through these channels.
+    pub event_handler: ChannelEventCollector,
+    pub contact_events: Receiver<ContactEvent>,
+    pub intersection_events: Receiver<IntersectionEvent>,
 }
 // Generate all of the Resources needed for physics!
 impl Default for PhysicsResource {
@@ -53,8 +252,11 @@ impl Default for PhysicsResource {
         let narrow_phase = NarrowPhase::new();
         let joint_set = JointSet::new();
         let ccd_solver = CCDSolver::new();
+        let query_pipeline = QueryPipeline::new();
         let physics_hooks = ();
-        let event_handler = ();
+        let (intersection_send, intersection_events) = unbounded();
+        let (contact_send, contact_events) = unbounded();
+        let event_handler = ChannelEventCollector::new(intersection_send, contact_send);
 
         PhysicsResource {
             gravity,
@@ -65,8 +267,11 @@ impl Default for PhysicsResource {
             narrow_phase,
             joint_set,
             ccd_solver,
+            query_pipeline,
             physics_hooks,
             event_handler,
+            contact_events,
+            intersection_events,
         }
     }
 }
@@ -86,5 +291,8 @@ impl PhysicsResource {
             &self.physics_hooks, 
             &self.event_handler,
         );
+
+        // Keep the query pipeline in sync with the new positions.
+        self.query_pipeline.update(&self.island_manager, bodies, colliders);
     }
 }
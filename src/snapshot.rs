@@ -0,0 +1,388 @@
+use std::fmt;
+use std::io::Read;
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use specs::{Component, Entity, Join, World, WorldExt, Builder};
+
+use crate::{
+    components::{PlayerCar, PhysicsObject, PreviousTransform, WheelSet, Engine, Gearbox, Tires, Steering, Trigger, RaceProgress, GhostCar, ControlInput, AiDriver, PlayerSlot, Recovery, SurfaceMaterial, TerrainMap, StaticObject, ModelName},
+    resources::{RigidBodyContainer, ColliderContainer, PhysicsResource, SimulationClock, LoadedLevel, GameRng, RaceSession, GhostRecorder, ReplayRecorder},
+    level::Level,
+};
+
+// The whole game at one moment: every entity with its components,
+// the rapier sets and pipeline structures and our own resources.
+//
+// The data is "SNAP", a u16 version, then the parts below one after
+// the other in bincode. Bump the version whenever a part changes.
+
+const MAGIC: &[u8; 4] = b"SNAP";
+const VERSION: u16 = 5;
+// Highest entity id a save may have, for its number of entities.
+const MAX_ID_FACTOR: u64 = 4;
+const MAX_ID_SLACK: u64 = 1024;
+
+// Every component an entity can have. Adding a component means adding it
+// here, in save_entities and in load_entities.
+#[derive(Default, Serialize, Deserialize)]
+struct EntityState {
+    id: u32,
+    player_car: Option<PlayerCar>,
+    physics_object: Option<PhysicsObject>,
+    previous_transform: Option<PreviousTransform>,
+    wheel_set: Option<WheelSet>,
+    engine: Option<Engine>,
+    gearbox: Option<Gearbox>,
+    tires: Option<Tires>,
+    steering: Option<Steering>,
+    trigger: Option<Trigger>,
+    race_progress: Option<RaceProgress>,
+    ghost_car: Option<GhostCar>,
+    control_input: Option<ControlInput>,
+    ai_driver: Option<AiDriver>,
+    player_slot: Option<PlayerSlot>,
+    recovery: Option<Recovery>,
+    surface_material: Option<SurfaceMaterial>,
+    terrain_map: Option<TerrainMap>,
+    static_object: Option<StaticObject>,
+    model_name: Option<ModelName>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    // Doesn't start with the magic bytes.
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    // bincode couldn't read or write a part.
+    Encoding(String),
+    // The saved level doesn't parse anymore.
+    Level(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "Not a saved game"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "Unsupported save version {}", version),
+            SnapshotError::Encoding(message) => write!(f, "Broken saved game: {}", message),
+            SnapshotError::Level(message) => write!(f, "Saved level can't be loaded: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        SnapshotError::Encoding(err.to_string())
+    }
+}
+
+pub fn save(world: &World) -> Result<Vec<u8>, SnapshotError> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+    // Rapier
+    let physics = world.read_resource::<PhysicsResource>();
+    bincode::serialize_into(&mut bytes, &world.read_resource::<RigidBodyContainer>().0)?;
+    bincode::serialize_into(&mut bytes, &world.read_resource::<ColliderContainer>().0)?;
+    bincode::serialize_into(&mut bytes, &physics.gravity)?;
+    bincode::serialize_into(&mut bytes, &physics.integration_parameters)?;
+    bincode::serialize_into(&mut bytes, &physics.island_manager)?;
+    bincode::serialize_into(&mut bytes, &physics.broad_phase)?;
+    bincode::serialize_into(&mut bytes, &physics.narrow_phase)?;
+    bincode::serialize_into(&mut bytes, &physics.joint_set)?;
+    bincode::serialize_into(&mut bytes, &physics.ccd_solver)?;
+    bincode::serialize_into(&mut bytes, &physics.query_pipeline)?;
+
+    // Our resources
+    bincode::serialize_into(&mut bytes, &world.read_resource::<LoadedLevel>().source)?;
+    bincode::serialize_into(&mut bytes, &*world.read_resource::<SimulationClock>())?;
+    bincode::serialize_into(&mut bytes, &*world.read_resource::<GameRng>())?;
+    bincode::serialize_into(&mut bytes, &*world.read_resource::<RaceSession>())?;
+    bincode::serialize_into(&mut bytes, &*world.read_resource::<GhostRecorder>())?;
+    bincode::serialize_into(&mut bytes, &*world.read_resource::<ReplayRecorder>())?;
+
+    // Entities
+    bincode::serialize_into(&mut bytes, &save_entities(world))?;
+
+    Ok(bytes)
+}
+
+pub fn load(bytes: &[u8], world: &mut World) -> Result<(), SnapshotError> {
+    // Fills an empty world with the saved game.
+    if bytes.len() < MAGIC.len() + 2 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let mut reader = &bytes[MAGIC.len() + 2..];
+
+    // Rapier, the pipeline and the event channels start fresh.
+    let rigidbodies = RigidBodyContainer(read_part(&mut reader)?);
+    let colliders = ColliderContainer(read_part(&mut reader)?);
+    // Read in the order they were written, struct fields are evaluated in order.
+    let physics = PhysicsResource {
+        gravity: read_part(&mut reader)?,
+        integration_parameters: read_part(&mut reader)?,
+        island_manager: read_part(&mut reader)?,
+        broad_phase: read_part(&mut reader)?,
+        narrow_phase: read_part(&mut reader)?,
+        joint_set: read_part(&mut reader)?,
+        ccd_solver: read_part(&mut reader)?,
+        query_pipeline: read_part(&mut reader)?,
+        ..PhysicsResource::default()
+    };
+
+    // Our resources
+    let source: String = read_part(&mut reader)?;
+    let clock: SimulationClock = read_part(&mut reader)?;
+    let rng: GameRng = read_part(&mut reader)?;
+    let race: RaceSession = read_part(&mut reader)?;
+    let ghosts: GhostRecorder = read_part(&mut reader)?;
+    let replays: ReplayRecorder = read_part(&mut reader)?;
+
+    // Entities
+    let entities: Vec<EntityState> = read_part(&mut reader)?;
+    check_entity_ids(&entities)?;
+
+    // The level was validated when it was first loaded.
+    let level = if source.is_empty() {
+        Level::default()
+    } else {
+        Level::parse(&source).map_err(|err| SnapshotError::Level(err.to_string()))?
+    };
+
+    world.insert(rigidbodies);
+    world.insert(colliders);
+    world.insert(physics);
+    world.insert(LoadedLevel { source, level });
+    world.insert(clock);
+    world.insert(rng);
+    world.insert(race);
+    world.insert(ghosts);
+    world.insert(replays);
+    load_entities(world, entities);
+
+    Ok(())
+}
+
+fn check_entity_ids(states: &[EntityState]) -> Result<(), SnapshotError> {
+    // Every id below the highest one gets created while loading, so
+    // a made up id near u32::MAX would never finish. Saved worlds only
+    // have a few free ids between the used ones.
+    let max_id = states.len() as u64 * MAX_ID_FACTOR + MAX_ID_SLACK;
+    let mut ids: Vec<u32> = states.iter().map(|state| state.id).collect();
+    ids.sort_unstable();
+    if let Some(id) = ids.iter().find(|id| **id as u64 > max_id) {
+        return Err(SnapshotError::Encoding(format!("entity id {} is too high for {} entities", id, states.len())));
+    }
+    if let Some(pair) = ids.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(SnapshotError::Encoding(format!("entity id {} is saved twice", pair[0])));
+    }
+    Ok(())
+}
+
+fn read_part<T: DeserializeOwned>(reader: &mut impl Read) -> Result<T, SnapshotError> {
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+fn save_entities(world: &World) -> Vec<EntityState> {
+    fn get<T: Component + Clone>(world: &World, entity: Entity) -> Option<T> {
+        world.read_storage::<T>().get(entity).cloned()
+    }
+
+    world.entities().join()
+        .map(|entity| EntityState {
+            id: entity.id(),
+            player_car: get(world, entity),
+            physics_object: get(world, entity),
+            previous_transform: get(world, entity),
+            wheel_set: get(world, entity),
+            engine: get(world, entity),
+            gearbox: get(world, entity),
+            tires: get(world, entity),
+            steering: get(world, entity),
+            trigger: get(world, entity),
+            race_progress: get(world, entity),
+            ghost_car: get(world, entity),
+            control_input: get(world, entity),
+            ai_driver: get(world, entity),
+            player_slot: get(world, entity),
+            recovery: get(world, entity),
+            surface_material: get(world, entity),
+            terrain_map: get(world, entity),
+            static_object: get(world, entity),
+            model_name: get(world, entity),
+        })
+        .collect()
+}
+
+fn load_entities(world: &mut World, mut states: Vec<EntityState>) {
+    fn insert<T: Component>(world: &World, entity: Entity, component: Option<T>) {
+        if let Some(component) = component {
+            world.write_storage::<T>().insert(entity, component).ok();
+        }
+    }
+
+    // Colliders and triggers refer to entities by id, so every entity
+    // has to get its old id back. A fresh world hands out ids in order,
+    // ids that were free when saving are created and deleted again.
+    states.sort_by_key(|state| state.id);
+    let mut states = states.into_iter().peekable();
+    let mut gaps = Vec::new();
+    while let Some(state) = states.peek() {
+        let entity = world.create_entity().build();
+        if entity.id() != state.id {
+            gaps.push(entity);
+            continue;
+        }
+        let state = states.next().unwrap();
+
+        insert(world, entity, state.player_car);
+        insert(world, entity, state.physics_object);
+        insert(world, entity, state.previous_transform);
+        insert(world, entity, state.wheel_set);
+        insert(world, entity, state.engine);
+        insert(world, entity, state.gearbox);
+        insert(world, entity, state.tires);
+        insert(world, entity, state.steering);
+        insert(world, entity, state.trigger);
+        insert(world, entity, state.race_progress);
+        insert(world, entity, state.ghost_car);
+        insert(world, entity, state.control_input);
+        insert(world, entity, state.ai_driver);
+        insert(world, entity, state.player_slot);
+        insert(world, entity, state.recovery);
+        insert(world, entity, state.surface_material);
+        insert(world, entity, state.terrain_map);
+        insert(world, entity, state.static_object);
+        insert(world, entity, state.model_name);
+    }
+
+    for entity in gaps {
+        world.delete_entity(entity).ok();
+    }
+    world.maintain();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GameContainer, GameInput, RacePhase};
+
+    // A straight with the finish line 15 meters ahead of the spawn.
+    const STRAIGHT: &str = r#"{
+        "spawn_points": [{ "position": [0.0, 2.0, 0.0] }],
+        "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "scale": [200.0, 1.0, 200.0] },
+        "triggers": [{ "kind": "finish_line", "position": [15.0, 2.0, 0.0], "half_extents": [1.0, 3.0, 10.0] }]
+    }"#;
+
+    fn game() -> GameContainer {
+        let mut game = GameContainer::create_with_seed(7);
+        let mut input = GameInput::new();
+        input.set_throttle(1.0);
+        for _ in 0..30 {
+            game.step_with_input(&input);
+        }
+        game
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut game = game();
+        let bytes = save(&game.world).unwrap();
+
+        let mut loaded = GameContainer::create_with_seed(0);
+        loaded.try_load_state(&bytes).unwrap();
+        assert_eq!(loaded.tick(), game.tick());
+        assert_eq!(loaded.state_hash(), game.state_hash());
+
+        // And both go on the same way.
+        let input = GameInput::new();
+        for _ in 0..30 {
+            game.step_with_input(&input);
+            loaded.step_with_input(&input);
+        }
+        assert_eq!(loaded.state_hash(), game.state_hash());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = save(&game().world).unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(load(&bytes, &mut World::new()), Err(SnapshotError::NotASnapshot)));
+        assert!(matches!(load(b"SNA", &mut World::new()), Err(SnapshotError::NotASnapshot)));
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut bytes = save(&game().world).unwrap();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(load(&bytes, &mut World::new()), Err(SnapshotError::UnsupportedVersion(version)) if version == VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let bytes = save(&game().world).unwrap();
+        let mut game = game();
+        let hash = game.state_hash();
+        for length in (MAGIC.len() + 2..bytes.len()).step_by(bytes.len() / 64) {
+            assert!(matches!(game.try_load_state(&bytes[..length]), Err(SnapshotError::Encoding(_))), "at {} bytes", length);
+        }
+        // A broken save leaves the game as it was.
+        assert_eq!(game.state_hash(), hash);
+    }
+
+    #[test]
+    fn rejects_entity_ids_out_of_range() {
+        let state = |id| EntityState { id, ..EntityState::default() };
+        assert!(check_entity_ids(&[state(0), state(5), state(2)]).is_ok());
+        assert!(matches!(check_entity_ids(&[state(0), state(u32::MAX)]), Err(SnapshotError::Encoding(_))));
+        assert!(matches!(check_entity_ids(&[state(3), state(3)]), Err(SnapshotError::Encoding(_))));
+    }
+
+    #[test]
+    fn restores_a_race_in_progress() {
+        let mut game = GameContainer::create_with_seed(2);
+        game.try_load_level(STRAIGHT).unwrap();
+        game.start_race(2);
+
+        // Drive through the line once, into the last lap.
+        let mut input = GameInput::new();
+        input.set_throttle(1.0);
+        for _ in 0..1200 {
+            game.step_with_input(&input);
+            if game.race_state().progress.lap == 2 {
+                break;
+            }
+        }
+        for _ in 0..10 {
+            game.step_with_input(&input);
+        }
+        assert_eq!(game.race_state().phase, RacePhase::Racing);
+        assert!(game.ghost_lap_time().is_some());
+
+        let mut loaded = GameContainer::create_with_seed(0);
+        loaded.try_load_state(&save(&game.world).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", loaded.race_state()), format!("{:?}", game.race_state()));
+        {
+            let saved = game.world.read_resource::<GhostRecorder>();
+            let restored = loaded.world.read_resource::<GhostRecorder>();
+            assert_eq!(restored.lap, saved.lap);
+            assert_eq!(restored.recording.len(), saved.recording.len());
+            assert_eq!(restored.best.as_ref().map(|best| best.lap_time), saved.best.as_ref().map(|best| best.lap_time));
+        }
+
+        // The restored race goes on to the same finish.
+        for _ in 0..300 {
+            game.step_with_input(&input);
+            loaded.step_with_input(&input);
+        }
+        assert_eq!(format!("{:?}", loaded.race_state()), format!("{:?}", game.race_state()));
+        assert_eq!(loaded.state_hash(), game.state_hash());
+    }
+}
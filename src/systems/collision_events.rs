@@ -0,0 +1,76 @@
+use rapier3d::prelude::{ColliderHandle, ContactEvent, NarrowPhase};
+use specs::{System, Write, Read};
+
+use crate::{resources::{ColliderContainer, PhysicsResource, GameEvents, SimulationClock}, entities::collider_entity, GameEvent, GameEventKind};
+
+// Turns rapier's collider events into GameEvents between entities.
+// Runs right after the physics step, so the contact impulses are known.
+pub struct CollisionEventSystem {}
+
+impl <'a>System<'a> for CollisionEventSystem {
+    type SystemData = (
+        Read<'a, ColliderContainer>,
+        Read<'a, PhysicsResource>,
+        Read<'a, SimulationClock>,
+        Write<'a, GameEvents>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (collider_set, physics_structures, clock, mut events) = data;
+
+        // Find the entities behind two colliders.
+        let entities_of = |handle_a: ColliderHandle, handle_b: ColliderHandle| {
+            let entity_a = collider_set.0.get(handle_a).and_then(collider_entity)?;
+            let entity_b = collider_set.0.get(handle_b).and_then(collider_entity)?;
+            Some((entity_a, entity_b))
+        };
+
+        while let Ok(event) = physics_structures.contact_events.try_recv() {
+            let (kind, handle_a, handle_b) = match event {
+                ContactEvent::Started(a, b) => (GameEventKind::ContactStarted, a, b),
+                ContactEvent::Stopped(a, b) => (GameEventKind::ContactStopped, a, b),
+            };
+            let (entity_a, entity_b) = match entities_of(handle_a, handle_b) {
+                Some(entities) => entities,
+                None => continue,
+            };
+
+            let (impulse, point) = contact_impulse(&physics_structures.narrow_phase, handle_a, handle_b);
+            events.push(GameEvent::new(kind, entity_a, entity_b, impulse, point, clock.tick));
+        }
+
+        while let Ok(event) = physics_structures.intersection_events.try_recv() {
+            let kind = match event.intersecting {
+                true => GameEventKind::IntersectionStarted,
+                false => GameEventKind::IntersectionStopped,
+            };
+            let (entity_a, entity_b) = match entities_of(event.collider1, event.collider2) {
+                Some(entities) => entities,
+                None => continue,
+            };
+
+            events.push(GameEvent::new(kind, entity_a, entity_b, 0.0, [0.0; 3], clock.tick));
+        }
+    }
+}
+
+fn contact_impulse(narrow_phase: &NarrowPhase, handle_a: ColliderHandle, handle_b: ColliderHandle) -> (f32, [f32; 3]) {
+    // Add up the impulses the solver used to push the colliders apart,
+    // and use the first contact as the point where they hit.
+    let pair = match narrow_phase.contact_pair(handle_a, handle_b) {
+        Some(pair) => pair,
+        None => return (0.0, [0.0; 3]),
+    };
+
+    let mut impulse = 0.0;
+    let mut point = None;
+    for manifold in pair.manifolds.iter() {
+        impulse += manifold.points.iter().map(|contact| contact.data.impulse).sum::<f32>();
+        if point.is_none() {
+            point = manifold.data.solver_contacts.first().map(|contact| contact.point);
+        }
+    }
+
+    let point = point.map_or([0.0; 3], |point| [point.x, point.y, point.z]);
+    (impulse, point)
+}
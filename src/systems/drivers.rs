@@ -0,0 +1,159 @@
+use nalgebra::{vector, Point3, Vector3};
+use specs::{System, Read, ReadStorage, WriteStorage, Join};
+
+use crate::{resources::{RigidBodyContainer, PlayerInputs}, components::{PhysicsObject, ControlInput, AiDriver, Steering, PlayerSlot}, GameInput};
+
+// How many waypoints ahead the AI looks for corners to brake for.
+const CORNER_LOOKAHEAD: usize = 6;
+// Throttle per m/s below the speed the AI wants.
+const THROTTLE_GAIN: f32 = 0.25;
+// Brake per m/s above the speed the AI wants.
+const BRAKE_GAIN: f32 = 0.2;
+
+// Decides what every car's driver does this step. Humans use the
+// input of their player slot, the AI follows its waypoints.
+pub struct DriverSystem {}
+
+impl <'a>System<'a> for DriverSystem {
+    type SystemData = (
+        ReadStorage<'a, PhysicsObject>,
+        ReadStorage<'a, Steering>,
+        ReadStorage<'a, PlayerSlot>,
+        WriteStorage<'a, AiDriver>,
+        WriteStorage<'a, ControlInput>,
+
+        Read<'a, RigidBodyContainer>,
+        Read<'a, PlayerInputs>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (physics_objects, steerings, slots, mut ai_drivers, mut control_inputs, rigidbody_set, inputs) = data;
+
+        for (control, slot) in (&mut control_inputs, &slots).join() {
+            control.0 = inputs.get(slot.0);
+        }
+
+        for (physics_object, steering, driver, control) in (&physics_objects, steerings.maybe(), &mut ai_drivers, &mut control_inputs).join() {
+            let rigidbody = match rigidbody_set.0.get(physics_object.rigidbody) {
+                Some(rigidbody) => rigidbody,
+                None => continue,
+            };
+            if driver.waypoints.len() < 2 {
+                control.0 = GameInput::new();
+                continue;
+            }
+
+            let position = Point3::from(*rigidbody.translation());
+            let forward = rigidbody.rotation().transform_vector(&vector![1.0, 0.0, 0.0]);
+            let forward_speed = rigidbody.linvel().dot(&forward);
+
+            // Move on once we're close to the waypoint we're heading to.
+            for _ in 0..driver.waypoints.len() {
+                if flat_distance(&position, &driver.waypoints[driver.target]) >= driver.waypoint_radius {
+                    break;
+                }
+                driver.target = (driver.target + 1) % driver.waypoints.len();
+            }
+
+            /* Steering */
+            // Aim at the first waypoint far enough ahead, so the car cuts
+            // smoothly through the line instead of zig-zagging.
+            let aim = (0..driver.waypoints.len())
+                .map(|offset| &driver.waypoints[(driver.target + offset) % driver.waypoints.len()])
+                .find(|waypoint| flat_distance(&position, waypoint) >= driver.lookahead)
+                .unwrap_or(&driver.waypoints[driver.target]);
+
+            // Positive is to the left, same as the steering input.
+            let local = rigidbody.rotation().inverse_transform_vector(&(aim - position));
+            let heading_error = (-local.z).atan2(local.x);
+            let max_angle = steering.map_or(0.6, |steering| steering.max_angle_at(forward_speed));
+            let steer = (heading_error / max_angle).clamp(-1.0, 1.0);
+
+            /* Speed */
+            // Find the slowest speed we need around the next corners, taking
+            // into account how much road there is left to brake on.
+            let mut target_speed = driver.max_speed;
+            let mut distance = flat_distance(&position, &driver.waypoints[driver.target]);
+            for offset in 0..CORNER_LOOKAHEAD {
+                let count = driver.waypoints.len();
+                let index = (driver.target + offset) % count;
+                let previous = &driver.waypoints[(index + count - 1) % count];
+                let corner = &driver.waypoints[index];
+                let next = &driver.waypoints[(index + 1) % count];
+
+                let corner_speed = corner_speed(previous, corner, next, driver.corner_grip).min(driver.max_speed);
+                // v² = v_corner² + 2 a d
+                let arrival_speed = (corner_speed * corner_speed + 2.0 * driver.braking * distance).sqrt();
+                target_speed = target_speed.min(arrival_speed);
+
+                distance += flat_distance(corner, next);
+            }
+
+            let mut ai_input = GameInput::new();
+            ai_input.set_steering(steer);
+            if forward_speed < target_speed {
+                ai_input.set_throttle((target_speed - forward_speed) * THROTTLE_GAIN + 0.2);
+            } else if forward_speed > 2.0 {
+                // Braking while stopped would put the gearbox in reverse.
+                ai_input.set_brake((forward_speed - target_speed) * BRAKE_GAIN);
+            }
+            control.0 = ai_input;
+        }
+    }
+}
+
+fn flat_distance(a: &Point3<f32>, b: &Point3<f32>) -> f32 {
+    // Distance ignoring height, so hills don't hide a waypoint.
+    vector![a.x - b.x, a.z - b.z].magnitude()
+}
+
+fn corner_speed(previous: &Point3<f32>, corner: &Point3<f32>, next: &Point3<f32>, grip: f32) -> f32 {
+    // How fast a car can turn at `corner` with `grip` m/s² sideways.
+    // The curvature is the turn angle over the length around the corner.
+    let incoming: Vector3<f32> = vector![corner.x - previous.x, 0.0, corner.z - previous.z];
+    let outgoing: Vector3<f32> = vector![next.x - corner.x, 0.0, next.z - corner.z];
+    if incoming.magnitude() <= 0.0 || outgoing.magnitude() <= 0.0 {
+        return f32::MAX;
+    }
+    let length = (incoming.magnitude() + outgoing.magnitude()) / 2.0;
+    let angle = incoming.angle(&outgoing);
+    if angle <= 1e-3 {
+        return f32::MAX;
+    }
+
+    let curvature = angle / length;
+    (grip / curvature).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::point;
+
+    use super::*;
+
+    #[test]
+    fn tighter_corners_are_slower() {
+        let start = point![0.0, 0.0, 0.0];
+        let corner = point![10.0, 0.0, 0.0];
+        let straight = corner_speed(&start, &corner, &point![20.0, 0.0, 0.0], 8.0);
+        assert_eq!(straight, f32::MAX);
+
+        let gentle = corner_speed(&start, &corner, &point![20.0, 0.0, 5.0], 8.0);
+        let hairpin = corner_speed(&start, &corner, &point![5.0, 0.0, 5.0], 8.0);
+        assert!(hairpin < gentle && gentle < straight);
+    }
+
+    #[test]
+    fn skill_drives_faster() {
+        let waypoints = vec![point![0.0, 0.0, 0.0], point![10.0, 0.0, 0.0], point![10.0, 0.0, 10.0]];
+        let careful = AiDriver::new(waypoints.clone(), 0.0);
+        let fast = AiDriver::new(waypoints.clone(), 1.0);
+
+        let plan = |driver: &AiDriver| {
+            corner_speed(&waypoints[0], &waypoints[1], &waypoints[2], driver.corner_grip).min(driver.max_speed)
+        };
+        assert!(plan(&careful) < plan(&fast));
+        assert!(careful.max_speed < fast.max_speed);
+        assert!(careful.braking < fast.braking);
+    }
+}
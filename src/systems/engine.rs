@@ -0,0 +1,118 @@
+use std::f32::consts::PI;
+
+use nalgebra::vector;
+use specs::{System, Read, ReadStorage, WriteStorage, Join};
+
+use crate::{resources::{RigidBodyContainer, PhysicsResource}, components::{PhysicsObject, WheelSet, Engine, Gearbox, GearboxMode, ControlInput}};
+
+// Turns the pedals into rpm, gears and the force that the wheels push with.
+pub struct EngineSystem {}
+
+impl <'a>System<'a> for EngineSystem {
+    type SystemData = (
+        ReadStorage<'a, PhysicsObject>,
+        ReadStorage<'a, WheelSet>,
+        WriteStorage<'a, Engine>,
+        WriteStorage<'a, Gearbox>,
+        ReadStorage<'a, ControlInput>,
+
+        Read<'a, RigidBodyContainer>,
+        Read<'a, PhysicsResource>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            physics_objects,
+            wheel_sets,
+            mut engines,
+            mut gearboxes,
+            control_inputs,
+            rigidbody_set,
+            physics_structures,
+        ) = data;
+
+        let dt = physics_structures.integration_parameters.dt;
+
+        for (physics_object, wheel_set, engine, gearbox, control) in (&physics_objects, &wheel_sets, &mut engines, &mut gearboxes, &control_inputs).join() {
+            let input = &control.0;
+            let rigidbody = match rigidbody_set.0.get(physics_object.rigidbody) {
+                Some(rigidbody) => rigidbody,
+                None => continue,
+            };
+
+            // Speed in the direction the car is looking at.
+            let forward = rigidbody.rotation().transform_vector(&vector![1.0, 0.0, 0.0]);
+            let forward_speed = rigidbody.linvel().dot(&forward);
+
+            // A little dead zone so a resting gamepad doesn't shift gears.
+            let accelerating = input.throttle() > 0.05;
+            let reversing = input.brake() > 0.05;
+
+            /* Gear changes */
+            gearbox.shift_timer = (gearbox.shift_timer - dt).max(0.0);
+
+            match gearbox.mode {
+                GearboxMode::Manual => {
+                    // Only shift once per key press.
+                    let shift_up = input.gear_up();
+                    let shift_down = input.gear_down();
+                    if shift_up && !gearbox.shift_up_held {
+                        gearbox.shift_to(gearbox.gear + 1);
+                    }
+                    if shift_down && !gearbox.shift_down_held {
+                        gearbox.shift_to(gearbox.gear - 1);
+                    }
+                    gearbox.shift_up_held = shift_up;
+                    gearbox.shift_down_held = shift_down;
+                }
+                GearboxMode::Automatic => {
+                    if reversing && forward_speed < 1.0 && gearbox.gear >= 0 {
+                        // Holding the brakes while stopped means going backwards.
+                        gearbox.shift_to(-1);
+                    } else if accelerating && forward_speed > -1.0 && gearbox.gear <= 0 {
+                        gearbox.shift_to(1);
+                    } else if gearbox.gear > 0 && !gearbox.is_shifting() {
+                        if engine.rpm > gearbox.upshift_rpm {
+                            gearbox.shift_to(gearbox.gear + 1);
+                        } else if engine.rpm < gearbox.downshift_rpm && gearbox.gear > 1 {
+                            gearbox.shift_to(gearbox.gear - 1);
+                        }
+                    }
+                }
+            }
+
+            /* Throttle */
+            // In reverse the brake pedal is the one that makes us go.
+            engine.throttle = match (gearbox.mode, gearbox.gear < 0) {
+                (GearboxMode::Automatic, true) => input.brake(),
+                _ => input.throttle(),
+            };
+
+            /* RPM */
+            let wheel_radius = wheel_set.wheels.first().map_or(0.5, |wheel| wheel.radius);
+            let ratio = gearbox.ratio();
+
+            if ratio == 0.0 || gearbox.is_shifting() {
+                // Clutch is open, the engine revs freely.
+                let target = engine.idle_rpm + engine.throttle * (engine.redline_rpm - engine.idle_rpm);
+                engine.rpm += (target - engine.rpm) * (5.0 * dt).min(1.0);
+            } else {
+                // Clutch is closed, the wheels decide the rpm.
+                let wheel_rpm = forward_speed / wheel_radius * 60.0 / (2.0 * PI);
+                engine.rpm = (wheel_rpm * ratio).max(engine.idle_rpm);
+            }
+
+            /* Force */
+            let grounded = wheel_set.any_grounded();
+            engine.drive_force = if ratio == 0.0 || gearbox.is_shifting() || !grounded {
+                0.0
+            } else if engine.rpm >= engine.redline_rpm {
+                // Rev limiter cuts the fuel.
+                0.0
+            } else {
+                engine.torque_at(engine.rpm) * engine.throttle * ratio / wheel_radius
+            };
+            engine.rpm = engine.rpm.min(engine.redline_rpm);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use nalgebra::vector;
+use specs::{System, Write, Read, ReadStorage, Entities, LazyUpdate, Join};
+
+use crate::{resources::{RigidBodyContainer, GhostRecorder, RaceSession, SimulationClock}, components::{PlayerCar, PhysicsObject, RaceProgress, GhostCar, PlayerSlot}, entities::create_ghost, ghost::{Ghost, GhostFrame}, RacePhase};
+
+// Records the player's laps and moves the ghost through the best one,
+// in step with the lap the player is driving.
+pub struct GhostSystem {}
+
+impl <'a>System<'a> for GhostSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, LazyUpdate>,
+        ReadStorage<'a, PlayerCar>,
+        ReadStorage<'a, PhysicsObject>,
+        ReadStorage<'a, RaceProgress>,
+        ReadStorage<'a, GhostCar>,
+        ReadStorage<'a, PlayerSlot>,
+
+        Write<'a, RigidBodyContainer>,
+        Write<'a, GhostRecorder>,
+        Read<'a, RaceSession>,
+        Read<'a, SimulationClock>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            lazy,
+            players,
+            physics_objects,
+            progresses,
+            ghost_cars,
+            slots,
+            mut rigidbody_set,
+            mut recorder,
+            race,
+            clock,
+        ) = data;
+
+        /* Recording */
+        // Finished too, the last lap ends on the same tick as the race.
+        if matches!(race.phase, RacePhase::Racing | RacePhase::Finished) {
+            // Only the first player's laps are recorded.
+            let player = (&players, &slots, &physics_objects, &progresses).join()
+                .find(|(_player, slot, _physics_object, _progress)| slot.0 == 0);
+            if let Some((_player, _slot, physics_object, progress)) = player {
+                // A new lap number means the last one just ended.
+                if recorder.lap != progress.lap {
+                    if recorder.lap != 0 {
+                        finish_lap(&mut recorder, progress, clock.fixed_dt);
+                    }
+                    recorder.lap = progress.lap;
+                    recorder.recording.clear();
+                }
+
+                if !progress.finished() {
+                    if let Some(rigidbody) = rigidbody_set.0.get(physics_object.rigidbody) {
+                        recorder.recording.push(GhostFrame::from_isometry(rigidbody.position()));
+                    }
+                }
+            }
+        }
+
+        /* Playback */
+        let best = match &recorder.best {
+            Some(best) => best,
+            None => return,
+        };
+
+        // The frame for the next step, so the ghost lands where the
+        // player was at the same moment of the best lap.
+        let index = recorder.recording.len();
+        let frame = match best.frame(index) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let mut ghost_found = false;
+        for (_ghost, physics_object) in (&ghost_cars, &physics_objects).join() {
+            ghost_found = true;
+            if let Some(rigidbody) = rigidbody_set.0.get_mut(physics_object.rigidbody) {
+                rigidbody.set_next_kinematic_position(frame.to_isometry());
+            }
+        }
+
+        if !ghost_found {
+            // First time we have a lap to show, put the ghost at its start.
+            let pos = vector![frame.position[0], frame.position[1], frame.position[2]];
+            let rot = frame.to_isometry().rotation.scaled_axis();
+            create_ghost(&entities, &lazy, pos, rot, &mut rigidbody_set);
+        }
+    }
+}
+
+fn finish_lap(recorder: &mut GhostRecorder, progress: &RaceProgress, frame_time: f32) {
+    // Keep the lap if it beats the ghost.
+    let lap_time = match progress.last_lap {
+        Some(lap_time) => lap_time,
+        None => return,
+    };
+    if recorder.best.as_ref().is_some_and(|best| best.lap_time <= lap_time) {
+        return;
+    }
+    recorder.best = Some(Ghost {
+        frame_time,
+        lap_time,
+        frames: std::mem::take(&mut recorder.recording),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Isometry3;
+
+    use super::*;
+    use crate::{GameContainer, GameInput};
+
+    // A straight with the finish line 15 meters ahead of the spawn.
+    const STRAIGHT: &str = r#"{
+        "spawn_points": [{ "position": [0.0, 2.0, 0.0] }],
+        "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "scale": [200.0, 1.0, 200.0] },
+        "triggers": [{ "kind": "finish_line", "position": [15.0, 2.0, 0.0], "half_extents": [1.0, 3.0, 10.0] }]
+    }"#;
+
+    fn lap(lap_time: f32, frames: usize) -> (RaceProgress, Vec<GhostFrame>) {
+        let progress = RaceProgress {
+            last_lap: Some(lap_time),
+            ..RaceProgress::new()
+        };
+        (progress, vec![GhostFrame::from_isometry(&Isometry3::identity()); frames])
+    }
+
+    #[test]
+    fn faster_laps_become_best() {
+        let mut recorder = GhostRecorder::default();
+
+        let (progress, frames) = lap(12.0, 3);
+        recorder.recording = frames;
+        finish_lap(&mut recorder, &progress, 0.5);
+        assert_eq!(recorder.best.as_ref().map(|best| best.lap_time), Some(12.0));
+
+        // A slower lap leaves the ghost alone.
+        let (progress, frames) = lap(13.0, 4);
+        recorder.recording = frames;
+        finish_lap(&mut recorder, &progress, 0.5);
+        assert_eq!(recorder.best.as_ref().map(|best| best.frames.len()), Some(3));
+
+        let (progress, frames) = lap(11.0, 2);
+        recorder.recording = frames;
+        finish_lap(&mut recorder, &progress, 0.5);
+        let best = recorder.best.unwrap();
+        assert_eq!((best.lap_time, best.frames.len(), best.frame_time), (11.0, 2, 0.5));
+    }
+
+    #[test]
+    fn records_the_last_lap_of_a_race() {
+        let mut game = GameContainer::create_with_seed(1);
+        game.try_load_level(STRAIGHT).unwrap();
+        game.start_race(1);
+
+        let mut input = GameInput::new();
+        input.set_throttle(1.0);
+        for _ in 0..1200 {
+            game.step_with_input(&input);
+            if game.race_state().phase == RacePhase::Finished {
+                break;
+            }
+        }
+
+        // The only lap is also the last one, it ends with the race.
+        assert_eq!(game.race_state().phase, RacePhase::Finished);
+        let lap_time = game.race_state().best_lap_time();
+        assert!(lap_time.is_some());
+        assert_eq!(game.ghost_lap_time(), lap_time);
+    }
+}
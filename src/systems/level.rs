@@ -0,0 +1,86 @@
+use nalgebra::{vector, point};
+use specs::{System, LazyUpdate, Write, Read, Entities};
+
+use crate::{resources::{RigidBodyContainer, ColliderContainer}, entities::{create_player, create_ramp, create_box, create_convex_hull, create_trimesh, create_heightfield, create_trigger, create_ai_car}, components::{TriggerKind, AiDriver}, level::{Level, PropDef}};
+
+// Build every entity described by a level. The level must be validated first.
+pub struct LevelLoadSystem<'l> {
+    pub level: &'l Level,
+}
+impl <'a, 'l>System<'a> for LevelLoadSystem<'l> {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, LazyUpdate>,
+
+        // Phyisics
+        Write<'a, RigidBodyContainer>,
+        Write<'a, ColliderContainer>,
+    );
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, lazy, mut rigidbodies, mut colliders) = data;
+
+        // Terrain
+        if let Some(terrain) = &self.level.terrain {
+            let scale = vector![terrain.scale[0], terrain.scale[1], terrain.scale[2]];
+            create_heightfield(&entities, &lazy, terrain.to_matrix(), scale, terrain.material, terrain.to_material_matrix(), &mut rigidbodies, &mut colliders);
+        }
+
+        // Create our player at the first spawn point.
+        let spawn = self.level.spawn_points[0];
+        let player_pos = vector![spawn.position[0], spawn.position[1], spawn.position[2]];
+        let player_rot = vector![0.0, spawn.yaw, 0.0];
+        create_player(&entities, &lazy, player_pos, player_rot, 0, &mut rigidbodies, &mut colliders);
+
+        // Opponents take the next spawn points.
+        let waypoints: Vec<_> = self.level.ai_waypoints().iter().map(|p| point![p[0], p[1], p[2]]).collect();
+        for (opponent, spawn) in self.level.opponents.iter().zip(self.level.spawn_points.iter().skip(1)) {
+            let pos = vector![spawn.position[0], spawn.position[1], spawn.position[2]];
+            let rot = vector![0.0, spawn.yaw, 0.0];
+            let driver = AiDriver::new(waypoints.clone(), opponent.skill);
+            create_ai_car(&entities, &lazy, pos, rot, driver, &mut rigidbodies, &mut colliders);
+        }
+
+        // Props
+        for prop in self.level.props.iter() {
+            let (position, rotation) = prop.transform();
+            let pos = vector![position[0], position[1], position[2]];
+            let rot = vector![rotation[0], rotation[1], rotation[2]];
+            let material = prop.material();
+
+            match prop {
+                PropDef::Ramp { .. } => {
+                    create_ramp(&entities, &lazy, pos, rot, material, &mut rigidbodies, &mut colliders);
+                }
+                PropDef::Box { half_extents, .. } => {
+                    let half_extents = vector![half_extents[0], half_extents[1], half_extents[2]];
+                    create_box(&entities, &lazy, pos, rot, half_extents, material, &mut rigidbodies, &mut colliders);
+                }
+                PropDef::ConvexHull { points, .. } => {
+                    let points: Vec<_> = points.iter().map(|p| point![p[0], p[1], p[2]]).collect();
+                    create_convex_hull(&entities, &lazy, pos, rot, &points, material, &mut rigidbodies, &mut colliders);
+                }
+                PropDef::Trimesh { vertices, indices, .. } => {
+                    let vertices = vertices.iter().map(|v| point![v[0], v[1], v[2]]).collect();
+                    create_trimesh(&entities, &lazy, pos, rot, vertices, indices.clone(), material, &mut rigidbodies, &mut colliders);
+                }
+            }
+        }
+
+        // Checkpoints, numbered in the order they have to be driven through.
+        for (index, checkpoint) in self.level.checkpoints.iter().enumerate() {
+            let pos = vector![checkpoint.position[0], checkpoint.position[1], checkpoint.position[2]];
+            let rot = vector![checkpoint.rotation[0], checkpoint.rotation[1], checkpoint.rotation[2]];
+            let half_extents = vector![checkpoint.half_extents[0], checkpoint.half_extents[1], checkpoint.half_extents[2]];
+            let kind = TriggerKind::Checkpoint(index as u32);
+            create_trigger(&entities, &lazy, pos, rot, half_extents, kind, &mut rigidbodies, &mut colliders);
+        }
+
+        // Every other trigger volume.
+        for trigger in self.level.triggers.iter() {
+            let pos = vector![trigger.position[0], trigger.position[1], trigger.position[2]];
+            let rot = vector![trigger.rotation[0], trigger.rotation[1], trigger.rotation[2]];
+            let half_extents = vector![trigger.half_extents[0], trigger.half_extents[1], trigger.half_extents[2]];
+            create_trigger(&entities, &lazy, pos, rot, half_extents, trigger.kind, &mut rigidbodies, &mut colliders);
+        }
+    }
+}
@@ -1,30 +1,120 @@
 
-use wasm_bindgen::prelude::wasm_bindgen;
-use specs::{Dispatcher, DispatcherBuilder, System, World, RunNow, WorldExt};
+use specs::{World, RunNow, WorldExt};
 
-use crate::GameKeysContainer;
+use crate::resources::{SimulationClock, PhysicsResource, GameEvents, ReplayRecorder, PlayerInputs};
 
-use self::{run_physics::PhysicsSystem, movement::MovementSystem};
+
+use self::{run_physics::PhysicsSystem, movement::MovementSystem, suspension::SuspensionSystem, engine::EngineSystem, tires::TireSystem, collision_events::CollisionEventSystem, triggers::TriggerSystem, race::RaceSystem, ghost::GhostSystem, drivers::DriverSystem, recovery::RecoverySystem};
 // Import our systems and create a
 // function out of it
 
 mod run_physics;
 mod movement;
+mod suspension;
+mod engine;
+mod tires;
+mod collision_events;
+mod triggers;
+mod race;
+mod ghost;
+mod drivers;
+mod recovery;
 pub mod init;
+pub mod level;
+
+pub fn run_systems(world: &mut World, elapsed: f32) {
+    // Add the time since the last frame and run as many fixed
+    // steps as fit in it. The leftover is kept for the next frame.
+    let steps = {
+        let mut clock = world.write_resource::<SimulationClock>();
+        clock.accumulator += elapsed.max(0.0);
+
+        let steps = ((clock.accumulator / clock.fixed_dt) as u32).min(clock.max_substeps);
+        clock.accumulator -= steps as f32 * clock.fixed_dt;
+        // If we hit the cap, drop the time we couldn't simulate.
+        clock.accumulator = clock.accumulator.min(clock.fixed_dt);
+        steps
+    };
+
+    for _ in 0..steps {
+        run_fixed_tick(world);
+    }
+
+    let mut clock = world.write_resource::<SimulationClock>();
+    clock.alpha = clock.accumulator / clock.fixed_dt;
+}
+
+pub fn run_fixed_tick(world: &mut World) {
+    // Every step simulates exactly the same amount of time.
+    {
+        let fixed_dt = world.read_resource::<SimulationClock>().fixed_dt;
+        world.write_resource::<PhysicsResource>().integration_parameters.dt = fixed_dt;
+        // Events from the last tick have been handed out by now.
+        world.write_resource::<GameEvents>().start_tick();
+
+        // Remember the input of every tick, replays depend on it.
+        let inputs = world.read_resource::<PlayerInputs>();
+        world.write_resource::<ReplayRecorder>().replay.push_inputs(&inputs.slots);
+    }
 
-pub fn run_systems(world: &mut World) {
     // We cannot use a Dispatcher in WebAssembly :(
+    {
+        // Run Driver System
+        let mut ds = DriverSystem {};
+        ds.run_now(world);
+    }
+    {
+        // Run Suspension System
+        let mut ss = SuspensionSystem {};
+        ss.run_now(world);
+    }
+    {
+        // Run Engine System
+        let mut es = EngineSystem {};
+        es.run_now(world);
+    }
     {  
         // Run Movement System
         let mut ms = MovementSystem {};
         ms.run_now(world);
     }
+    {
+        // Run Tire System
+        let mut ts = TireSystem {};
+        ts.run_now(world);
+    }
     { 
         // Run Physics Step System
         let mut ps = PhysicsSystem {};
         ps.run_now(world);
         
     }
+    {
+        // Run Collision Event System
+        let mut ces = CollisionEventSystem {};
+        ces.run_now(world);
+    }
+    {
+        // Run Trigger System
+        let mut trs = TriggerSystem {};
+        trs.run_now(world);
+    }
+    {
+        // Run Race System
+        let mut rs = RaceSystem {};
+        rs.run_now(world);
+    }
+    {
+        // Run Recovery System
+        let mut rcs = RecoverySystem {};
+        rcs.run_now(world);
+    }
+    {
+        // Run Ghost System
+        let mut gs = GhostSystem {};
+        gs.run_now(world);
+    }
 
+    world.write_resource::<SimulationClock>().tick += 1;
     world.maintain();
 }
@@ -1,8 +1,13 @@
-use nalgebra::{vector, Point3, Quaternion, Matrix, Vector3, Rotation2, Rotation, Rotation3, Matrix3, Const, ArrayStorage, Matrix2, point};
+use nalgebra::vector;
 use rapier3d::prelude::RigidBody;
 use specs::{System, Write, Read, Entities, ReadStorage, WriteStorage, Join};
 
-use crate::{resources::{RigidBodyContainer, ColliderContainer}, GameKeysContainer, components::{PlayerCar, PhysicsObject}, log, GameKeys};
+use crate::{resources::{RigidBodyContainer, ColliderContainer, PhysicsResource, RaceSession}, components::{PlayerCar, PhysicsObject, Engine, Gearbox, GearboxMode, WheelSet, Steering, Axle, ControlInput}, log};
+
+// Force the brakes can stop the car with, split between the wheels.
+const BRAKE_FORCE: f32 = 4000.0;
+// Extra engine force while the boost button is held.
+const BOOST_MULTIPLIER: f32 = 1.5;
 
 
 
@@ -13,11 +18,16 @@ impl <'a>System<'a> for MovementSystem {
         Entities<'a>,
         ReadStorage<'a, PlayerCar>,
         WriteStorage<'a, PhysicsObject>,
-
+        ReadStorage<'a, Engine>,
+        ReadStorage<'a, Gearbox>,
+        WriteStorage<'a, WheelSet>,
+        WriteStorage<'a, Steering>,
+        ReadStorage<'a, ControlInput>,
 
         Write<'a, RigidBodyContainer>,
         Write<'a, ColliderContainer>,
-        Read<'a, GameKeysContainer>,
+        Read<'a, PhysicsResource>,
+        Read<'a, RaceSession>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
@@ -25,13 +35,25 @@ impl <'a>System<'a> for MovementSystem {
             entities,
             player,
             physics_objects,
+            engines,
+            gearboxes,
+            mut wheel_sets,
+            mut steerings,
+            control_inputs,
             mut rigidbody_set,
-            mut collider_set,
-            keys,
+            _collider_set,
+            physics_structures,
+            race,
         ) = data;
 
+        let dt = physics_structures.integration_parameters.dt;
+
+        // During the countdown the cars wait on the line with the brakes on.
+        let locked = race.inputs_locked();
+
         // Get the physics_object and of all players
-        for (physics_object, player, _ent) in (&physics_objects, &player, &entities).join() {
+        for (physics_object, player, engine, gearbox, wheel_set, steering, control, _ent) in (&physics_objects, &player, engines.maybe(), gearboxes.maybe(), &mut wheel_sets, (&mut steerings).maybe(), &control_inputs, &entities).join() {
+            let input = &control.0;
             let rigidbody_handle = physics_object.rigidbody;
             
             
@@ -41,43 +63,74 @@ impl <'a>System<'a> for MovementSystem {
             }
 
             let rigidbody = rigidbody.unwrap();
-            // Check if the colliders are intersecting with others.
+
+            // Clear what we asked the wheels to do last step.
+            for wheel in wheel_set.wheels.iter_mut() {
+                wheel.drive_force = 0.0;
+                wheel.brake_force = 0.0;
+                wheel.locked = false;
+            }
+
+            /* Steering */
+            // Turn the wheels instead of the car, so it can't spin in place.
+            if let Some(steering) = steering {
+                // Positive goes left, negative goes right.
+                let target = if locked { 0.0 } else { input.steering() };
+
+                let forward = rigidbody.rotation().transform_vector(&vector![1.0, 0.0, 0.0]);
+                let forward_speed = rigidbody.linvel().dot(&forward);
+                steering.update(target, forward_speed, dt);
+
+                for wheel in wheel_set.wheels.iter_mut().filter(|wheel| wheel.steered) {
+                    wheel.steer_angle = steering.angle;
+                }
+            }
+
+            // Only the wheels standing on the ground can push the car.
  
             if player.touching_ground {
-            // Variables to change
-            let mut forward_force = vector![0.0, 0.0, 0.0];
-            let mut torque = vector![0.0, 0.0, 0.0];
-            
             /* Throttle */
-            if keys.get(GameKeys::Acceleration as usize) {
-                // Slam on the pedal.
-                forward_force = vector![320.0, 0.0, 0.0];
+            // The engine already decided how hard the wheels push,
+            // split it between the driven wheels on the ground.
+            let mut drive_force = engine.map_or(0.0, |engine| engine.drive_force);
+            if locked {
+                drive_force = 0.0;
             }
-            if keys.get(GameKeys::Brakes as usize) {
-                // Slam on the reverse.
-                forward_force = vector![-120.0, 0.0, 0.0];
+            if input.boost() {
+                drive_force *= BOOST_MULTIPLIER;
             }
+            let driven_wheels = wheel_set.wheels.iter()
+                .filter(|wheel| wheel.driven && wheel.grounded)
+                .count();
 
-            // TODO: Make it so torque's magnitude changes with current speed.
-            /* Steering */
-            if keys.get(GameKeys::Left as usize) {
-                // Go left.
-                torque = vector![0.0, 630.0, 0.0];
-            }
-            if keys.get(GameKeys::Right as usize) {
-                // Go right.
-                torque = vector![0.0, -630.0, 0.0];
-            }
+            /* Brakes */
+            // An automatic gearbox in reverse uses the brake key as throttle.
+            let reversing = gearbox.is_some_and(|gearbox| {
+                gearbox.mode == GearboxMode::Automatic && gearbox.gear < 0
+            });
+            let brake = if locked {
+                1.0
+            } else if reversing {
+                0.0
+            } else {
+                input.brake()
+            };
+            let wheel_count = wheel_set.wheels.len() as f32;
 
-            // Change the rotation to be relative to where the
-            // Car is looking at.
-            forward_force = rigidbody.rotation().transform_vector(&forward_force);
-            
-            // TODO: apply traction to angvel.
+            for wheel in wheel_set.wheels.iter_mut() {
+                if wheel.driven && wheel.grounded {
+                    wheel.drive_force = drive_force / driven_wheels as f32;
+                }
+                wheel.brake_force = brake * BRAKE_FORCE / wheel_count;
+
+                // The handbrake locks the rear wheels.
+                if input.handbrake() && wheel.axle == Axle::Rear {
+                    wheel.drive_force = 0.0;
+                    wheel.locked = true;
+                }
+            }
 
-            // Apply velocity.
-            rigidbody.apply_impulse(forward_force, true);
-            rigidbody.apply_torque(torque, true);
+            // The TireSystem turns all of this into forces.
 
             }
         }
@@ -0,0 +1,127 @@
+use std::cmp::Ordering;
+
+use specs::{System, Write, Read, WriteStorage, Entities, Join};
+
+use crate::{resources::{RaceSession, TriggerEvents, PhysicsResource}, components::{RaceProgress, TriggerKind}, RacePhase};
+
+// Runs the countdown and moves every car with a RaceProgress
+// through the checkpoints it drives into.
+pub struct RaceSystem {}
+
+impl <'a>System<'a> for RaceSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, RaceProgress>,
+
+        Write<'a, RaceSession>,
+        Read<'a, TriggerEvents>,
+        Read<'a, PhysicsResource>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, mut progresses, mut race, trigger_events, physics_structures) = data;
+
+        let dt = physics_structures.integration_parameters.dt;
+
+        match race.phase {
+            RacePhase::Idle | RacePhase::Finished => return,
+            RacePhase::Countdown => {
+                race.countdown -= dt;
+                if race.countdown <= 0.0 {
+                    // Green light, the clock starts now.
+                    race.countdown = 0.0;
+                    race.phase = RacePhase::Racing;
+                    race.time = 0.0;
+                }
+                return;
+            }
+            RacePhase::Racing => race.time += dt,
+        }
+
+        let time = race.time;
+        for (progress, entity) in (&mut progresses, &entities).join() {
+            if progress.finished() {
+                continue;
+            }
+            progress.current_lap = time - progress.lap_start;
+
+            for event in trigger_events.entered(entity.id()) {
+                drive_through(&race, progress, event.kind, time);
+                if progress.finished() {
+                    break;
+                }
+            }
+        }
+
+        update_positions(&entities, &mut progresses);
+
+        // The race is over once every car crossed the line.
+        if (&progresses).join().all(|progress| progress.finished()) {
+            race.phase = RacePhase::Finished;
+        }
+    }
+}
+
+fn drive_through(race: &RaceSession, progress: &mut RaceProgress, kind: TriggerKind, time: f32) {
+    let count = race.checkpoint_count;
+    let all_passed = progress.next_checkpoint >= count;
+
+    match kind {
+        TriggerKind::Checkpoint(index) => {
+            // Checkpoint 0 doubles as the line when there is no finish.
+            if index == 0 && all_passed && !race.has_finish_line {
+                progress.complete_lap(time, race.laps);
+                if progress.finished() {
+                    return;
+                }
+            }
+
+            if index == progress.next_checkpoint {
+                progress.pass_checkpoint(time);
+            } else if index > progress.next_checkpoint {
+                progress.missed_checkpoint = true;
+            } else if index + 1 < progress.next_checkpoint {
+                // Going back to an older checkpoint means driving backwards.
+                progress.wrong_way = true;
+            }
+            // At the start of a lap, the last checkpoint is behind us.
+            if progress.next_checkpoint == 0 && count > 1 && index == count - 1 {
+                progress.missed_checkpoint = false;
+                progress.wrong_way = true;
+            }
+        }
+        TriggerKind::FinishLine => {
+            if all_passed {
+                progress.complete_lap(time, race.laps);
+            } else if progress.next_checkpoint > 0 {
+                progress.missed_checkpoint = true;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn update_positions(entities: &Entities, progresses: &mut WriteStorage<RaceProgress>) {
+    // Finished cars go by finish time, the rest by how far they got
+    // and who got there first.
+    let mut order: Vec<_> = (entities, &*progresses).join()
+        .map(|(entity, progress)| {
+            let finish_time = progress.finish_time.unwrap_or(f32::MAX);
+            let last_split = progress.lap_start + progress.splits.last().copied().unwrap_or(0.0);
+            (entity, finish_time, progress.lap, progress.next_checkpoint, last_split)
+        })
+        .collect();
+
+    order.sort_by(|a, b| {
+        a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal)
+            .then(b.2.cmp(&a.2))
+            .then(b.3.cmp(&a.3))
+            .then(a.4.partial_cmp(&b.4).unwrap_or(Ordering::Equal))
+    });
+
+    for (place, (entity, ..)) in order.iter().enumerate() {
+        if let Some(progress) = progresses.get_mut(*entity) {
+            progress.position = place as u32 + 1;
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use nalgebra::{vector, point, Isometry3, Point3};
+use rapier3d::prelude::{ColliderHandle, ColliderSet, InteractionGroups, Ray, RigidBodySet};
+use specs::{System, Write, Read, ReadStorage, WriteStorage, Entities, Join};
+
+use crate::{resources::{RigidBodyContainer, ColliderContainer, PhysicsResource, TriggerEvents, LoadedLevel}, components::{PhysicsObject, PreviousTransform, WheelSet, Steering, ControlInput, RaceProgress, Recovery, TriggerKind}};
+
+// The car counts as flipped once its up axis points this far from the sky.
+const FLIPPED_UP: f32 = 0.3;
+// Slower than this (m/s) a flipped car isn't going to land on its wheels.
+const STUCK_SPEED: f32 = 2.0;
+// Seconds a car has to be stuck before it gets put back.
+const FLIP_DELAY: f32 = 2.0;
+// Respawned cars drop from this high above the ground onto their wheels.
+const RESPAWN_HEIGHT: f32 = 3.0;
+// The ground is searched for from this high up.
+const GROUND_RAY_HEIGHT: f32 = 500.0;
+
+// Puts cars back on their wheels: when they're stuck upside down, when
+// the driver presses reset, or when they drive into a kill zone.
+pub struct RecoverySystem {}
+
+impl <'a>System<'a> for RecoverySystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, PhysicsObject>,
+        WriteStorage<'a, Recovery>,
+        ReadStorage<'a, ControlInput>,
+        ReadStorage<'a, RaceProgress>,
+        WriteStorage<'a, WheelSet>,
+        WriteStorage<'a, Steering>,
+        WriteStorage<'a, PreviousTransform>,
+
+        Write<'a, RigidBodyContainer>,
+        Read<'a, ColliderContainer>,
+        Read<'a, PhysicsResource>,
+        Read<'a, TriggerEvents>,
+        Read<'a, LoadedLevel>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            physics_objects,
+            mut recoveries,
+            control_inputs,
+            progresses,
+            mut wheel_sets,
+            mut steerings,
+            mut previous_transforms,
+            mut rigidbody_set,
+            collider_set,
+            physics_structures,
+            trigger_events,
+            loaded,
+        ) = data;
+
+        let dt = physics_structures.integration_parameters.dt;
+
+        for (entity, physics_object, recovery, control, progress, wheel_set, steering) in (&entities, &physics_objects, &mut recoveries, control_inputs.maybe(), progresses.maybe(), (&mut wheel_sets).maybe(), (&mut steerings).maybe()).join() {
+            let rigidbody = match rigidbody_set.0.get(physics_object.rigidbody) {
+                Some(rigidbody) => rigidbody,
+                None => continue,
+            };
+            let forward = rigidbody.rotation().transform_vector(&vector![1.0, 0.0, 0.0]);
+            let up = rigidbody.rotation().transform_vector(&vector![0.0, 1.0, 0.0]);
+            let position = *rigidbody.translation();
+            let speed = rigidbody.linvel().norm();
+
+            /* Respawn points */
+            let mut killed = false;
+            for event in trigger_events.entered(entity.id()) {
+                let remember = match event.kind {
+                    // During a race, only the checkpoint the car just passed counts.
+                    TriggerKind::Checkpoint(index) => progress.is_none_or(|progress| progress.next_checkpoint == index + 1),
+                    TriggerKind::Respawn => true,
+                    TriggerKind::KillZone => {
+                        killed = true;
+                        false
+                    }
+                    _ => false,
+                };
+                if !remember {
+                    continue;
+                }
+
+                let trigger = physics_objects.get(entities.entity(event.trigger))
+                    .and_then(|trigger| rigidbody_set.0.get(trigger.rigidbody));
+                if let Some(trigger) = trigger {
+                    recovery.respawn_point = Some(Point3::from(*trigger.translation()));
+                    recovery.respawn_yaw = (-forward.z).atan2(forward.x);
+                }
+            }
+
+            /* Stuck */
+            if up.y < FLIPPED_UP && speed < STUCK_SPEED {
+                recovery.upside_down += dt;
+            } else {
+                recovery.upside_down = 0.0;
+            }
+
+            /* Manual reset */
+            let reset = control.is_some_and(|control| control.0.reset());
+            let pressed = reset && !recovery.reset_held;
+            recovery.reset_held = reset;
+
+            if !(killed || pressed || recovery.upside_down > FLIP_DELAY) {
+                continue;
+            }
+
+            /* Respawn */
+            // The last checkpoint first, then right where the car is
+            // (unless that's a kill zone), then the start of the level.
+            let mut candidates = Vec::new();
+            if let Some(point) = recovery.respawn_point {
+                candidates.push((point, recovery.respawn_yaw));
+            }
+            if !killed {
+                candidates.push((Point3::from(position), (-forward.z).atan2(forward.x)));
+            }
+            let spawn = loaded.level.spawn_points.first()
+                .map_or((point![0.0, 5.0, 0.0], 0.0), |spawn| (Point3::from(spawn.position), spawn.yaw));
+            candidates.push(spawn);
+
+            let own_colliders = &physics_object.colliders;
+            let safe = candidates.iter().find_map(|(point, yaw)| {
+                find_ground(&physics_structures, &collider_set.0, &rigidbody_set.0, own_colliders, point.x, point.z)
+                    .map(|ground| (point![point.x, ground + RESPAWN_HEIGHT, point.z], *yaw))
+            });
+            // Without any ground, the level's spawn point is all we have.
+            let (point, yaw) = safe.unwrap_or(spawn);
+
+            let isometry = Isometry3::new(point.coords, vector![0.0, yaw, 0.0]);
+            if let Some(rigidbody) = rigidbody_set.0.get_mut(physics_object.rigidbody) {
+                rigidbody.set_position(isometry, true);
+                rigidbody.set_linvel(vector![0.0, 0.0, 0.0], true);
+                rigidbody.set_angvel(vector![0.0, 0.0, 0.0], true);
+            }
+            // Don't draw the car sliding across the map to get there.
+            previous_transforms.insert(entity, PreviousTransform(isometry)).ok();
+
+            // Start over with the wheels hanging and straight.
+            if let Some(wheel_set) = wheel_set {
+                for wheel in wheel_set.wheels.iter_mut() {
+                    wheel.compression = 0.0;
+                    wheel.in_contact = false;
+                    wheel.grounded = false;
+                    wheel.surface = None;
+                    wheel.suspension_force = 0.0;
+                    wheel.slip_ratio = 0.0;
+                    wheel.slip_angle = 0.0;
+                    wheel.steer_angle = 0.0;
+                }
+            }
+            if let Some(steering) = steering {
+                steering.input = 0.0;
+                steering.angle = 0.0;
+            }
+            recovery.upside_down = 0.0;
+        }
+    }
+}
+
+fn find_ground(physics_structures: &PhysicsResource, colliders: &ColliderSet, rigidbodies: &RigidBodySet, own_colliders: &[ColliderHandle], x: f32, z: f32) -> Option<f32> {
+    // Height of the highest static ground at x, z. Cars and triggers
+    // don't count, nobody wants to land on top of another car.
+    let filter: &dyn Fn(ColliderHandle) -> bool = &|handle| {
+        !own_colliders.contains(&handle)
+            && colliders.get(handle).is_some_and(|collider| {
+                !collider.is_sensor()
+                    && collider.parent()
+                        .and_then(|parent| rigidbodies.get(parent))
+                        .is_none_or(|rigidbody| rigidbody.is_static())
+            })
+    };
+
+    let ray = Ray::new(point![x, GROUND_RAY_HEIGHT, z], vector![0.0, -1.0, 0.0]);
+    physics_structures.query_pipeline.cast_ray(
+        colliders,
+        &ray,
+        2.0 * GROUND_RAY_HEIGHT,
+        true,
+        InteractionGroups::all(),
+        Some(filter),
+    ).map(|(_handle, toi)| ray.point_at(toi).y)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::UnitQuaternion;
+    use rapier3d::prelude::RigidBodyHandle;
+    use specs::WorldExt;
+
+    use super::*;
+    use crate::{components::PlayerCar, GameContainer, GameInput};
+
+    // Flat ground, a checkpoint 15 meters ahead and a kill zone after it.
+    const TRACK: &str = r#"{
+        "spawn_points": [{ "position": [0.0, 2.0, 0.0] }],
+        "terrain": { "heights": [[0.0, 0.0], [0.0, 0.0]], "scale": [200.0, 1.0, 200.0] },
+        "checkpoints": [{ "position": [15.0, 2.0, 0.0], "half_extents": [1.0, 3.0, 10.0] }],
+        "triggers": [{ "kind": "kill_zone", "position": [40.0, 2.0, 0.0], "half_extents": [1.0, 3.0, 10.0] }]
+    }"#;
+
+    fn car(game: &GameContainer) -> RigidBodyHandle {
+        let players = game.world.read_storage::<PlayerCar>();
+        let physics_objects = game.world.read_storage::<PhysicsObject>();
+        (&players, &physics_objects).join().next().unwrap().1.rigidbody
+    }
+
+    // Position, up axis and speed of the player's car.
+    fn pose(game: &GameContainer) -> (Point3<f32>, f32, f32) {
+        let rigidbody_set = game.world.read_resource::<RigidBodyContainer>();
+        let rigidbody = &rigidbody_set.0[car(game)];
+        let up = rigidbody.rotation().transform_vector(&vector![0.0, 1.0, 0.0]);
+        let speed = rigidbody.linvel().norm() + rigidbody.angvel().norm();
+        (Point3::from(*rigidbody.translation()), up.y, speed)
+    }
+
+    #[test]
+    fn flipped_cars_get_back_on_their_wheels() {
+        let mut game = GameContainer::create_with_seed(4);
+        game.try_load_level(TRACK).unwrap();
+        let input = GameInput::new();
+        for _ in 0..60 {
+            game.step_with_input(&input);
+        }
+
+        // Lay the car on its roof.
+        {
+            let handle = car(&game);
+            let mut rigidbody_set = game.world.write_resource::<RigidBodyContainer>();
+            let rigidbody = &mut rigidbody_set.0[handle];
+            let mut position = *rigidbody.position();
+            position.rotation = UnitQuaternion::from_euler_angles(std::f32::consts::PI, 0.0, 0.0);
+            position.translation.vector.y = 2.0;
+            rigidbody.set_position(position, true);
+        }
+
+        let dt = game.world.read_resource::<PhysicsResource>().integration_parameters.dt;
+        let mut upright = None;
+        for _ in 0..((FLIP_DELAY / dt) as usize + 120) {
+            game.step_with_input(&input);
+            let (position, up, speed) = pose(&game);
+            if up > 0.99 {
+                upright = Some((position, speed));
+                break;
+            }
+        }
+
+        let (position, speed) = upright.expect("the car was never put back");
+        assert!((position.y - RESPAWN_HEIGHT).abs() < 0.01, "respawned at {}", position.y);
+        assert_eq!(speed, 0.0);
+    }
+
+    #[test]
+    fn kill_zones_respawn_at_the_last_checkpoint() {
+        let mut game = GameContainer::create_with_seed(4);
+        game.try_load_level(TRACK).unwrap();
+        game.start_race(1);
+
+        let mut input = GameInput::new();
+        input.set_throttle(1.0);
+        let mut respawn = None;
+        let mut last_x = 0.0;
+        for _ in 0..1200 {
+            game.step_with_input(&input);
+            let (position, _up, speed) = pose(&game);
+            if position.x < last_x - 5.0 {
+                respawn = Some((position, speed));
+                break;
+            }
+            last_x = position.x;
+        }
+
+        let (position, speed) = respawn.expect("the kill zone never sent the car back");
+        assert!((position.x - 15.0).abs() < 0.01 && position.z.abs() < 0.01, "respawned at {}", position);
+        assert!((position.y - RESPAWN_HEIGHT).abs() < 0.01);
+        assert_eq!(speed, 0.0);
+    }
+}
@@ -1,8 +1,6 @@
-use rapier3d::{prelude::{JointSet, ColliderSet, RigidBodySet, IntegrationParameters, PhysicsPipeline, IslandManager, BroadPhase, NarrowPhase, CCDSolver}};
-use specs::{System, Write, Join, Read, ReadStorage, Entity, Entities, WriteStorage};
+use specs::{System, Write, Join, ReadStorage, Entities, WriteStorage};
 
-use nalgebra::{Vector3, vector};
-use crate::{resources::{ColliderContainer, RigidBodyContainer, PhysicsResource}, components::{PlayerCar, PhysicsObject}};
+use crate::{resources::{ColliderContainer, RigidBodyContainer, PhysicsResource}, components::{PlayerCar, PhysicsObject, WheelSet, PreviousTransform}};
 
 pub struct PhysicsSystem {}
 impl <'a>System<'a> for PhysicsSystem {
@@ -13,6 +11,8 @@ impl <'a>System<'a> for PhysicsSystem {
 
         WriteStorage<'a, PlayerCar>,
         ReadStorage<'a, PhysicsObject>,
+        ReadStorage<'a, WheelSet>,
+        WriteStorage<'a, PreviousTransform>,
         Entities<'a>,
     );
     fn run(&mut self, data: Self::SystemData) {
@@ -24,28 +24,29 @@ impl <'a>System<'a> for PhysicsSystem {
 
             mut players,
             physics_objects,
+            wheel_sets,
+            mut previous_transforms,
             entities,
         ) = data;
 
+        // Remember where everything was, so we can render in between steps.
+        for (physics_object, entity) in (&physics_objects, &entities).join() {
+            if let Some(rigidbody) = rigidbodies.0.get(physics_object.rigidbody) {
+                let previous = PreviousTransform(*rigidbody.position());
+                previous_transforms.insert(entity, previous).ok();
+            }
+        }
+
         // Run the simulation with the physics_structure's tick.
         physics_structures.step(&mut rigidbodies.0, &mut colliders.0);
 
         // Get our player entity.
         
-        for (player, physics_object, _ent) in (&mut players, &physics_objects, &entities).join() {
-
-            let mut player_touching_ground = false;
-
-            // Check if our collider is touching any other collider.
-            for _contact_pair in physics_structures.narrow_phase.contacts_with(
-                physics_object.colliders[0]
-            ) {
-                // Set touching ground to true
-                player_touching_ground = true;
-            }
+        for (player, wheel_set, _ent) in (&mut players, wheel_sets.maybe(), &entities).join() {
 
-             // Uptade the value.
-            player.touching_ground = player_touching_ground;
+            // Any wheel on the ground counts, since the chassis floats on the suspension.
+            // The chassis touching something doesn't, that's a wall or the car's roof.
+            player.touching_ground = wheel_set.is_some_and(|wheels| wheels.any_grounded());
         }
 
         /*
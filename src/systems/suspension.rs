@@ -0,0 +1,125 @@
+use nalgebra::{vector, Point3};
+use rapier3d::prelude::{Collider, ColliderHandle, InteractionGroups, Ray, RigidBody};
+use specs::{System, Write, Read, ReadStorage, WriteStorage, Entities, Join};
+
+use crate::{resources::{RigidBodyContainer, ColliderContainer, PhysicsResource}, components::{PhysicsObject, WheelSet, SurfaceMaterial, TerrainMap}, entities::collider_entity, log};
+
+// A wheel only stands on the ground when the surface faces the car's
+// up axis at least this much (about 60 degrees), anything steeper is a wall.
+const GROUND_NORMAL_MIN: f32 = 0.5;
+
+// Casts a ray for every wheel and pushes the car up with a spring.
+pub struct SuspensionSystem {}
+
+impl <'a>System<'a> for SuspensionSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, PhysicsObject>,
+        WriteStorage<'a, WheelSet>,
+        ReadStorage<'a, SurfaceMaterial>,
+        ReadStorage<'a, TerrainMap>,
+
+        Write<'a, RigidBodyContainer>,
+        Read<'a, ColliderContainer>,
+        Read<'a, PhysicsResource>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            physics_objects,
+            mut wheel_sets,
+            materials,
+            terrain_maps,
+            mut rigidbody_set,
+            collider_set,
+            physics_structures,
+        ) = data;
+
+        let dt = physics_structures.integration_parameters.dt;
+
+        for (physics_object, wheel_set) in (&physics_objects, &mut wheel_sets).join() {
+            let rigidbody: Option<&mut RigidBody> = rigidbody_set.0.get_mut(physics_object.rigidbody);
+            if rigidbody.is_none() {
+                log("None when fetching rigidbody from rigidbody_set.");
+                continue;
+            }
+            let rigidbody = rigidbody.unwrap();
+
+            // The rays shouldn't hit the car they belong to, or trigger volumes.
+            let own_colliders = &physics_object.colliders;
+            let colliders = &collider_set.0;
+            let filter: &dyn Fn(ColliderHandle) -> bool = &|handle| {
+                !own_colliders.contains(&handle)
+                    && colliders.get(handle).is_some_and(|collider| !collider.is_sensor())
+            };
+
+            // Every wheel pushes along the car's up axis.
+            let car_up = rigidbody.rotation().transform_vector(&vector![0.0, 1.0, 0.0]);
+
+            for wheel in wheel_set.wheels.iter_mut() {
+                // Shoot the ray from the mount point downwards.
+                let origin = rigidbody.position() * wheel.mount;
+                let ray = Ray::new(origin, -car_up);
+                let max_length = wheel.rest_length + wheel.radius;
+
+                let hit = physics_structures.query_pipeline.cast_ray_and_get_normal(
+                    &collider_set.0,
+                    &ray,
+                    max_length,
+                    true,
+                    InteractionGroups::all(),
+                    Some(filter),
+                );
+
+                let previous_compression = wheel.compression;
+
+                match hit {
+                    Some((handle, intersection)) => {
+                        // The spring ends where the wheel touches the ground.
+                        let spring_length = (intersection.toi - wheel.radius).max(0.0);
+                        wheel.compression = (wheel.rest_length - spring_length).max(0.0);
+                        wheel.in_contact = true;
+                        wheel.contact_point = ray.point_at(intersection.toi);
+                        wheel.contact_normal = intersection.normal;
+                        wheel.grounded = intersection.normal.dot(&car_up) >= GROUND_NORMAL_MIN;
+                        wheel.surface = colliders.get(handle).and_then(collider_entity);
+                        wheel.material = colliders.get(handle).map_or_else(SurfaceMaterial::default, |collider| {
+                            surface_material(collider, &wheel.contact_point, &entities, &materials, &terrain_maps)
+                        });
+                    }
+                    None => {
+                        // The wheel is hanging in the air.
+                        wheel.compression = 0.0;
+                        wheel.in_contact = false;
+                        wheel.grounded = false;
+                        wheel.surface = None;
+                        wheel.suspension_force = 0.0;
+                        continue;
+                    }
+                }
+
+                // Spring pushes with the compression, the damper
+                // fights how fast the compression is changing.
+                let compression_speed = (wheel.compression - previous_compression) / dt;
+                let force = wheel.stiffness * wheel.compression + wheel.damping * compression_speed;
+                // Springs can only push, never pull the car down.
+                wheel.suspension_force = force.max(0.0);
+
+                rigidbody.apply_force_at_point(car_up * wheel.suspension_force, wheel.contact_point, true);
+            }
+        }
+    }
+}
+
+fn surface_material(collider: &Collider, point: &Point3<f32>, entities: &Entities, materials: &ReadStorage<SurfaceMaterial>, terrain_maps: &ReadStorage<TerrainMap>) -> SurfaceMaterial {
+    // Terrain changes from point to point, everything else is one material.
+    let entity = match collider_entity(collider) {
+        Some(id) => entities.entity(id),
+        None => return SurfaceMaterial::default(),
+    };
+    if let Some(terrain) = terrain_maps.get(entity) {
+        return terrain.material_at(&collider.position().inverse_transform_point(point));
+    }
+    materials.get(entity).copied().unwrap_or_default()
+}
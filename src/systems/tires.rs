@@ -0,0 +1,140 @@
+use nalgebra::{vector, Unit, UnitQuaternion};
+use rapier3d::prelude::RigidBody;
+use specs::{System, Write, Read, ReadStorage, WriteStorage, Join};
+
+use crate::{resources::{RigidBodyContainer, PhysicsResource}, components::{PhysicsObject, WheelSet, Tires}, log};
+
+// Below this speed the slip angle is too noisy to be useful.
+const MIN_SLIP_SPEED: f32 = 0.5;
+
+// Turns what the wheels are asked to do into grip forces on the ground.
+pub struct TireSystem {}
+
+impl <'a>System<'a> for TireSystem {
+    type SystemData = (
+        ReadStorage<'a, PhysicsObject>,
+        WriteStorage<'a, WheelSet>,
+        ReadStorage<'a, Tires>,
+
+        Write<'a, RigidBodyContainer>,
+        Read<'a, PhysicsResource>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            physics_objects,
+            mut wheel_sets,
+            tires,
+            mut rigidbody_set,
+            physics_structures,
+        ) = data;
+
+        let dt = physics_structures.integration_parameters.dt;
+
+        for (physics_object, wheel_set, tires) in (&physics_objects, &mut wheel_sets, &tires).join() {
+            let rigidbody: Option<&mut RigidBody> = rigidbody_set.0.get_mut(physics_object.rigidbody);
+            if rigidbody.is_none() {
+                log("None when fetching rigidbody from rigidbody_set.");
+                continue;
+            }
+            let rigidbody = rigidbody.unwrap();
+
+            // Every wheel on the ground carries a part of the car's mass.
+            let grounded_wheels = wheel_set.wheels.iter().filter(|wheel| wheel.grounded).count();
+            if grounded_wheels == 0 {
+                for wheel in wheel_set.wheels.iter_mut() {
+                    wheel.longitudinal_force = 0.0;
+                    wheel.lateral_force = 0.0;
+                }
+                continue;
+            }
+            let mass_per_wheel = rigidbody.mass() / grounded_wheels as f32;
+
+            let car_forward = rigidbody.rotation().transform_vector(&vector![1.0, 0.0, 0.0]);
+            let car_up = Unit::new_normalize(rigidbody.rotation().transform_vector(&vector![0.0, 1.0, 0.0]));
+
+            for wheel in wheel_set.wheels.iter_mut() {
+                if !wheel.grounded {
+                    wheel.longitudinal_force = 0.0;
+                    wheel.lateral_force = 0.0;
+                    continue;
+                }
+                let params = tires.for_axle(wheel.axle);
+                // Slippery ground turns less of the load into grip.
+                let surface = wheel.material.properties();
+                let load = wheel.suspension_force * surface.grip;
+
+                // Directions of the wheel, turned by the steering and
+                // flattened on the ground.
+                let normal = wheel.contact_normal;
+                let wheel_forward = UnitQuaternion::from_axis_angle(&car_up, wheel.steer_angle) * car_forward;
+                let forward = wheel_forward - normal * wheel_forward.dot(&normal);
+                if forward.norm() < 0.001 {
+                    // The car is pointing straight into the ground.
+                    continue;
+                }
+                let forward = forward.normalize();
+                let side = normal.cross(&forward);
+
+                // How fast the tire slides over the ground.
+                let velocity = rigidbody.velocity_at_point(&wheel.contact_point);
+                let forward_speed = velocity.dot(&forward);
+                let side_speed = velocity.dot(&side);
+
+                /* Longitudinal */
+                // Brakes and rolling resistance push against the movement, but never past stopping.
+                let stopping_force = mass_per_wheel * forward_speed.abs() / dt;
+                let rolling_force = surface.rolling_resistance * wheel.suspension_force;
+                let brake_force = (wheel.brake_force + rolling_force).min(stopping_force) * forward_speed.signum();
+                let requested = wheel.drive_force - brake_force;
+
+                // The wheels have no spin of their own, so the slip ratio isn't
+                // measured. It's worked back from the requested force instead:
+                // the tire slips just enough to give it, and past the peak of the
+                // curve the force is capped. That stands in for wheelspin and
+                // locked brakes, but the tire stops slipping as soon as less is
+                // asked of it, a real wheel would take a moment to spin down.
+                let stiffness = params.longitudinal.stiffness() * load;
+                let longitudinal = if wheel.locked {
+                    // A locked wheel fully slides against the movement.
+                    wheel.slip_ratio = -forward_speed.signum();
+                    let sliding_force = params.longitudinal.evaluate(1.0) * load;
+                    -forward_speed.signum() * sliding_force.min(stopping_force)
+                } else {
+                    wheel.slip_ratio = if stiffness > 0.0 {
+                        (requested / stiffness).clamp(-1.0, 1.0)
+                    } else {
+                        0.0
+                    };
+                    let max_longitudinal = params.longitudinal.evaluate(wheel.slip_ratio) * load;
+                    requested.clamp(-max_longitudinal.abs(), max_longitudinal.abs())
+                };
+
+                /* Lateral */
+                wheel.slip_angle = side_speed.atan2(forward_speed.abs().max(MIN_SLIP_SPEED));
+                let mut lateral = -params.lateral.evaluate(wheel.slip_angle) * load;
+
+                // Friction circle: the more the tire pushes forward, the
+                // less it can grip sideways. This is what makes drifts happen.
+                let peak_longitudinal = params.longitudinal.d * load;
+                if wheel.locked {
+                    // Sliding tires barely hold sideways.
+                    lateral *= 0.3;
+                } else if peak_longitudinal > 0.0 {
+                    let used = (longitudinal / peak_longitudinal).clamp(-1.0, 1.0);
+                    lateral *= (1.0 - used * used).sqrt();
+                }
+
+                // Don't push harder than what it takes to stop sliding sideways.
+                let stopping_side_force = mass_per_wheel * side_speed.abs() / dt;
+                lateral = lateral.clamp(-stopping_side_force, stopping_side_force);
+
+                wheel.longitudinal_force = longitudinal;
+                wheel.lateral_force = lateral;
+
+                let force = forward * longitudinal + side * lateral;
+                rigidbody.apply_force_at_point(force, wheel.contact_point, true);
+            }
+        }
+    }
+}
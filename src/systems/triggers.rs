@@ -0,0 +1,60 @@
+use specs::{System, Write, Read, WriteStorage, Entities};
+
+use crate::{resources::{GameEvents, TriggerEvent, TriggerEvents}, components::Trigger, GameEventKind};
+
+// Keeps track of who is inside every trigger, using the
+// intersection events the CollisionEventSystem collected.
+pub struct TriggerSystem {}
+
+impl <'a>System<'a> for TriggerSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Trigger>,
+
+        Read<'a, GameEvents>,
+        Write<'a, TriggerEvents>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, mut triggers, events, mut trigger_events) = data;
+
+        trigger_events.current.clear();
+
+        for event in events.current.iter() {
+            let entered = match event.kind {
+                GameEventKind::IntersectionStarted => true,
+                GameEventKind::IntersectionStopped => false,
+                _ => continue,
+            };
+
+            // Either side can be the trigger, rapier doesn't keep an order.
+            for (trigger_id, other_id) in [(event.entity_a, event.entity_b), (event.entity_b, event.entity_a)] {
+                let trigger_entity = entities.entity(trigger_id);
+                if !entities.is_alive(trigger_entity) {
+                    continue;
+                }
+                let trigger = match triggers.get_mut(trigger_entity) {
+                    Some(trigger) => trigger,
+                    None => continue,
+                };
+
+                // Only report real changes, so entering twice is a no-op.
+                if entered == trigger.contains(other_id) {
+                    continue;
+                }
+                if entered {
+                    trigger.occupants.push(other_id);
+                } else {
+                    trigger.occupants.retain(|occupant| *occupant != other_id);
+                }
+
+                trigger_events.current.push(TriggerEvent {
+                    trigger: trigger_id,
+                    kind: trigger.kind,
+                    entity: other_id,
+                    entered,
+                });
+            }
+        }
+    }
+}
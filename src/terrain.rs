@@ -0,0 +1,413 @@
+use nalgebra::{DMatrix, Isometry3, Point3, Vector3};
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "web")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Generates heightfield terrain from a seed, so a whole map fits in a
+// few numbers. Heights are 0.0 to 1.0, the collider's scale does the rest.
+
+// Largest grid we generate, rapier gets slow past this.
+const MAX_SIZE: usize = 1025;
+const MAX_OCTAVES: u32 = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainParams {
+    // Heights per side of the square grid.
+    pub size: usize,
+    // Layers of noise, every one adds smaller details.
+    pub octaves: u32,
+    // Hills across the map for the first layer.
+    pub frequency: f32,
+    // How much of the last layer's height the next one gets.
+    pub persistence: f32,
+    // How much smaller the features of the next layer are.
+    pub lacunarity: f32,
+    // Highest the noise goes, 1.0 is the full scale of the collider.
+    pub height: f32,
+
+    // Thermal erosion: ground steeper than the talus slides down.
+    pub erosion_passes: u32,
+    // Height difference between neighbours that still stays in place.
+    pub erosion_talus: f32,
+    // Part of the extra height that slides every pass.
+    pub erosion_rate: f32,
+
+    // Points (x, z) of a track the terrain is flattened along, in the same
+    // space as the rest of the level. Three or more points make a loop.
+    pub track: Vec<[f32; 2]>,
+    pub track_width: f32,
+    // Distance next to the track where the ground blends back into the hills.
+    pub track_falloff: f32,
+}
+
+impl Default for TerrainParams {
+    fn default() -> Self {
+        TerrainParams {
+            size: 65,
+            octaves: 5,
+            frequency: 4.0,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            height: 0.3,
+
+            erosion_passes: 20,
+            erosion_talus: 0.01,
+            erosion_rate: 0.25,
+
+            track: Vec::new(),
+            track_width: 20.0,
+            track_falloff: 20.0,
+        }
+    }
+}
+
+impl TerrainParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.size < 2 || self.size > MAX_SIZE {
+            return Err(format!("terrain size must be between 2 and {}", MAX_SIZE));
+        }
+        if self.octaves == 0 || self.octaves > MAX_OCTAVES {
+            return Err(format!("terrain octaves must be between 1 and {}", MAX_OCTAVES));
+        }
+        let values = [
+            self.frequency, self.persistence, self.lacunarity, self.height,
+            self.erosion_talus, self.erosion_rate, self.track_width, self.track_falloff,
+        ];
+        if values.iter().any(|value| !value.is_finite() || *value < 0.0) {
+            return Err("terrain parameters must be positive numbers".to_string());
+        }
+        if self.erosion_rate > 1.0 {
+            return Err("terrain erosion rate can't be more than 1".to_string());
+        }
+        if self.track.iter().flatten().any(|value| !value.is_finite()) {
+            return Err("terrain track has non finite points".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn generate(seed: u64, params: &TerrainParams, scale: [f32; 3]) -> DMatrix<f32> {
+    // Rows go along z and columns along x, like rapier's heights.
+    let last = (params.size - 1) as f32;
+    let mut heights = DMatrix::from_fn(params.size, params.size, |row, column| {
+        fractal_noise(seed, column as f32 / last, row as f32 / last, params) * params.height
+    });
+
+    for _ in 0..params.erosion_passes {
+        erode(&mut heights, params.erosion_talus, params.erosion_rate);
+    }
+    flatten_track(&mut heights, params, scale);
+
+    heights
+}
+
+// Ready to draw triangles of a heightfield, the same ones rapier collides with.
+#[cfg_attr(feature = "web", wasm_bindgen)]
+#[derive(Clone, Debug, Default)]
+pub struct TerrainMesh {
+    // x, y, z of every height.
+    vertices: Vec<f32>,
+    normals: Vec<f32>,
+    // u, v from 0.0 to 1.0 over the whole map.
+    uvs: Vec<f32>,
+    // Three vertices per triangle, counter clockwise seen from above.
+    indices: Vec<u32>,
+}
+
+#[cfg_attr(feature = "web", wasm_bindgen)]
+impl TerrainMesh {
+    pub fn vertices(&self) -> Vec<f32> {
+        self.vertices.clone()
+    }
+    pub fn normals(&self) -> Vec<f32> {
+        self.normals.clone()
+    }
+    pub fn uvs(&self) -> Vec<f32> {
+        self.uvs.clone()
+    }
+    pub fn indices(&self) -> Vec<u32> {
+        self.indices.clone()
+    }
+}
+
+impl TerrainMesh {
+    pub fn build(heights: &DMatrix<f32>, scale: &Vector3<f32>, position: &Isometry3<f32>) -> TerrainMesh {
+        // One vertex per height, placed like rapier places them: rows
+        // along z, columns along x, centered on the collider.
+        let (rows, columns) = heights.shape();
+        if rows < 2 || columns < 2 {
+            return TerrainMesh::default();
+        }
+        let (last_row, last_column) = ((rows - 1) as f32, (columns - 1) as f32);
+
+        let mut points = Vec::with_capacity(rows * columns);
+        let mut mesh = TerrainMesh::default();
+        for row in 0..rows {
+            for column in 0..columns {
+                let local = Point3::new(
+                    (column as f32 / last_column - 0.5) * scale.x,
+                    heights[(row, column)] * scale.y,
+                    (row as f32 / last_row - 0.5) * scale.z,
+                );
+                points.push(position * local);
+                mesh.uvs.extend_from_slice(&[column as f32 / last_column, row as f32 / last_row]);
+            }
+        }
+
+        // Two triangles per cell, split along the same diagonal as rapier's,
+        // from the next row's corner to the next column's.
+        let vertex = |row: usize, column: usize| (row * columns + column) as u32;
+        for row in 0..rows - 1 {
+            for column in 0..columns - 1 {
+                let (p00, p10) = (vertex(row, column), vertex(row + 1, column));
+                let (p01, p11) = (vertex(row, column + 1), vertex(row + 1, column + 1));
+                mesh.indices.extend_from_slice(&[p00, p10, p01, p10, p11, p01]);
+            }
+        }
+
+        // Every vertex gets the normals of the triangles around it, the
+        // bigger triangles count more. Smooth, but true to the slopes.
+        let mut normals = vec![Vector3::zeros(); points.len()];
+        for triangle in mesh.indices.chunks(3) {
+            let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+            let normal = (points[b] - points[a]).cross(&(points[c] - points[a]));
+            normals[a] += normal;
+            normals[b] += normal;
+            normals[c] += normal;
+        }
+
+        for (point, normal) in points.iter().zip(normals.iter()) {
+            let normal = normal.try_normalize(1.0e-6).unwrap_or_else(Vector3::y);
+            mesh.vertices.extend_from_slice(&[point.x, point.y, point.z]);
+            mesh.normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+        }
+        mesh
+    }
+}
+
+fn fractal_noise(seed: u64, u: f32, v: f32, params: &TerrainParams) -> f32 {
+    // Layers of value noise, each smaller and weaker than the last.
+    // Scaled back to 0.0 to 1.0.
+    let mut frequency = params.frequency;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut max = 0.0;
+    for octave in 0..params.octaves {
+        let layer_seed = seed.wrapping_add(octave as u64);
+        total += value_noise(layer_seed, u * frequency, v * frequency) * amplitude;
+        max += amplitude;
+        frequency *= params.lacunarity;
+        amplitude *= params.persistence;
+    }
+    if max > 0.0 { total / max } else { 0.0 }
+}
+
+fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    // Random values on a grid, smoothly blended in between.
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (smoothstep(x - x0), smoothstep(y - y0));
+    let (ix, iy) = (x0 as i64, y0 as i64);
+
+    let top = lerp(lattice(seed, ix, iy), lattice(seed, ix + 1, iy), tx);
+    let bottom = lerp(lattice(seed, ix, iy + 1), lattice(seed, ix + 1, iy + 1), tx);
+    lerp(top, bottom, ty)
+}
+
+fn lattice(seed: u64, x: i64, y: i64) -> f32 {
+    // The same SplitMix64 mix as GameRng, on the seed and grid point.
+    let mut z = seed
+        ^ (x as u64).wrapping_mul(0x9E3779B97F4A7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn erode(heights: &mut DMatrix<f32>, talus: f32, rate: f32) {
+    // Every point too much higher than a neighbour gives it some ground.
+    // Moves are collected first, so the order we go through doesn't matter.
+    let (rows, columns) = heights.shape();
+    let mut moved = DMatrix::zeros(rows, columns);
+    for row in 0..rows {
+        for column in 0..columns {
+            let height = heights[(row, column)];
+            let neighbours = [
+                (row.wrapping_sub(1), column),
+                (row + 1, column),
+                (row, column.wrapping_sub(1)),
+                (row, column + 1),
+            ];
+            for (n_row, n_column) in neighbours {
+                if n_row >= rows || n_column >= columns {
+                    continue;
+                }
+                let difference = height - heights[(n_row, n_column)];
+                if difference > talus {
+                    // A quarter, the point can lose ground to all four sides.
+                    let amount = rate * (difference - talus) * 0.25;
+                    moved[(row, column)] -= amount;
+                    moved[(n_row, n_column)] += amount;
+                }
+            }
+        }
+    }
+    *heights += moved;
+}
+
+fn flatten_track(heights: &mut DMatrix<f32>, params: &TerrainParams, scale: [f32; 3]) {
+    // Across the track the ground is flat, along it it follows the hills.
+    let track = &params.track;
+    if track.len() < 2 {
+        return;
+    }
+    let mut segments: Vec<_> = track.windows(2).map(|pair| (pair[0], pair[1])).collect();
+    if track.len() > 2 {
+        segments.push((track[track.len() - 1], track[0]));
+    }
+
+    let original = heights.clone();
+    let (rows, columns) = heights.shape();
+    let half_width = params.track_width * 0.5;
+    let reach = half_width + params.track_falloff;
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let x = (column as f32 / (columns - 1) as f32 - 0.5) * scale[0];
+            let z = (row as f32 / (rows - 1) as f32 - 0.5) * scale[2];
+
+            // Closest point of the track.
+            let (distance, closest) = segments.iter()
+                .map(|(a, b)| closest_on_segment([x, z], *a, *b))
+                .fold((f32::MAX, [0.0, 0.0]), |best, next| if next.0 < best.0 { next } else { best });
+            if distance > reach {
+                continue;
+            }
+
+            let blend = if distance <= half_width || params.track_falloff <= 0.0 {
+                1.0
+            } else {
+                1.0 - smoothstep((distance - half_width) / params.track_falloff)
+            };
+            let target = sample(&original, closest, scale);
+            heights[(row, column)] = lerp(heights[(row, column)], target, blend);
+        }
+    }
+}
+
+fn closest_on_segment(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> (f32, [f32; 2]) {
+    let (dx, dz) = (b[0] - a[0], b[1] - a[1]);
+    let length_squared = dx * dx + dz * dz;
+    let t = if length_squared > 0.0 {
+        (((point[0] - a[0]) * dx + (point[1] - a[1]) * dz) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let closest = [a[0] + dx * t, a[1] + dz * t];
+    let distance = ((point[0] - closest[0]).powi(2) + (point[1] - closest[1]).powi(2)).sqrt();
+    (distance, closest)
+}
+
+fn sample(heights: &DMatrix<f32>, point: [f32; 2], scale: [f32; 3]) -> f32 {
+    // Height between the grid points at (x, z), like the collider's surface.
+    let (rows, columns) = heights.shape();
+    let column = ((point[0] / scale[0] + 0.5) * (columns - 1) as f32).clamp(0.0, (columns - 1) as f32);
+    let row = ((point[1] / scale[2] + 0.5) * (rows - 1) as f32).clamp(0.0, (rows - 1) as f32);
+
+    let (c0, r0) = (column.floor() as usize, row.floor() as usize);
+    let (c1, r1) = ((c0 + 1).min(columns - 1), (r0 + 1).min(rows - 1));
+    let (tc, tr) = (column - c0 as f32, row - r0 as f32);
+
+    let top = lerp(heights[(r0, c0)], heights[(r0, c1)], tc);
+    let bottom = lerp(heights[(r1, c0)], heights[(r1, c1)], tc);
+    lerp(top, bottom, tr)
+}
+
+fn smoothstep(t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapier3d::parry::{query::{Ray, RayCast}, shape::HeightField};
+
+    const SCALE: [f32; 3] = [1000.0, 100.0, 1000.0];
+
+    #[test]
+    fn seeds_give_the_same_terrain() {
+        let params = TerrainParams { size: 33, ..TerrainParams::default() };
+        assert_eq!(generate(7, &params, SCALE), generate(7, &params, SCALE));
+        assert_ne!(generate(7, &params, SCALE), generate(8, &params, SCALE));
+    }
+
+    #[test]
+    fn track_is_flat_across_its_width() {
+        // A straight track along x through the middle, 40 wide. With 65
+        // heights per side the rows are 15.6 apart, so the middle three
+        // rows are all on the track.
+        let params = TerrainParams {
+            track: vec![[-400.0, 0.0], [400.0, 0.0]],
+            track_width: 40.0,
+            ..TerrainParams::default()
+        };
+        let heights = generate(3, &params, SCALE);
+        for column in 8..=56 {
+            let middle = heights[(32, column)];
+            assert!((heights[(31, column)] - middle).abs() < 1e-5, "column {}", column);
+            assert!((heights[(33, column)] - middle).abs() < 1e-5, "column {}", column);
+        }
+
+        // Away from the track the hills are still there.
+        let hills = heights.row(0);
+        assert!(hills.max() - hills.min() > 0.01);
+    }
+
+    fn mesh_height(mesh: &TerrainMesh, x: f32, z: f32) -> Option<f32> {
+        // Height of the triangle under (x, z), seen from above.
+        let vertex = |index: u32| {
+            let start = index as usize * 3;
+            Point3::new(mesh.vertices[start], mesh.vertices[start + 1], mesh.vertices[start + 2])
+        };
+        mesh.indices.chunks(3).find_map(|triangle| {
+            let [a, b, c] = [vertex(triangle[0]), vertex(triangle[1]), vertex(triangle[2])];
+            let area = (b.x - a.x) * (c.z - a.z) - (c.x - a.x) * (b.z - a.z);
+            let u = ((b.x - x) * (c.z - z) - (c.x - x) * (b.z - z)) / area;
+            let v = ((c.x - x) * (a.z - z) - (a.x - x) * (c.z - z)) / area;
+            let w = 1.0 - u - v;
+            let inside = [u, v, w].iter().all(|weight| *weight >= -1.0e-5);
+            if inside { Some(a.y * u + b.y * v + c.y * w) } else { None }
+        })
+    }
+
+    #[test]
+    fn mesh_matches_heightfield() {
+        // Not flat, so every cell's diagonal changes the surface.
+        let heights = DMatrix::from_row_slice(3, 3, &[
+            0.0, 0.2, 0.9,
+            0.5, 1.0, 0.1,
+            0.3, 0.0, 0.7,
+        ]);
+        let scale = Vector3::new(20.0, 10.0, 30.0);
+        let mesh = TerrainMesh::build(&heights, &scale, &Isometry3::identity());
+        let heightfield = HeightField::new(heights, scale);
+
+        for i in 1..40 {
+            for j in 1..40 {
+                let x = (j as f32 / 40.0 - 0.5) * scale.x;
+                let z = (i as f32 / 40.0 - 0.5) * scale.z;
+                let ray = Ray::new(Point3::new(x, 100.0, z), -Vector3::y());
+                let toi = heightfield.cast_local_ray(&ray, f32::MAX, true).expect("ray missed the heightfield");
+                let expected = 100.0 - toi;
+                let actual = mesh_height(&mesh, x, z).expect("point outside the mesh");
+                assert!((expected - actual).abs() < 1.0e-3, "at ({}, {}): {} != {}", x, z, actual, expected);
+            }
+        }
+    }
+}
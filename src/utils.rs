@@ -1,5 +1,7 @@
+#[cfg(feature = "web")]
 use wasm_bindgen::prelude::wasm_bindgen;
 
+#[cfg(feature = "web")]
 #[wasm_bindgen]
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
@@ -11,3 +13,17 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+#[cfg(feature = "web")]
+pub fn random_seed() -> u64 {
+    // Use the browser's random numbers.
+    (js_sys::Math::random() * u32::MAX as f64) as u64
+}
+
+#[cfg(not(feature = "web"))]
+pub fn random_seed() -> u64 {
+    // Without a browser, the clock is random enough for a seed.
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
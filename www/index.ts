@@ -1,4 +1,4 @@
-import {GameContainer, set_panic_hook, GameObjectContainer, PhysicsType, GameKeys, GameKeysContainer} from "game-test";
+import {GameContainer, set_panic_hook, GameObjectContainer, PhysicsType, GameKeys, GameKeysContainer, GameInput, GameEventKind, RacePhase, GameInputSet} from "game-test";
 import * as THREE from 'three';
 import { PlaneGeometry, RepeatWrapping } from "three";
 import { ConvexGeometry } from 'three/examples/jsm/geometries/ConvexGeometry'
@@ -14,17 +14,31 @@ const CAMERA_WIDTH: number = 40;
 
 // Create the 3js scene.
 const scene = new THREE.Scene();
-const camara = new THREE.OrthographicCamera(
-    -3.2 * CAMERA_WIDTH, //Left
-    3.2 * CAMERA_WIDTH,  //Ruight
-    2.4 * CAMERA_WIDTH,  //Top
-    -2.4 * CAMERA_WIDTH, //Bottom
-    0.01, //Near
-    5000,  //Far
-);
-// Move camera to look at center.
-camara.position.set(-100, 100, -100);
-camara.lookAt(0, 0, 0);
+function create_camera(): THREE.OrthographicCamera {
+    const camera = new THREE.OrthographicCamera(
+        -3.2 * CAMERA_WIDTH, //Left
+        3.2 * CAMERA_WIDTH,  //Ruight
+        2.4 * CAMERA_WIDTH,  //Top
+        -2.4 * CAMERA_WIDTH, //Bottom
+        0.01, //Near
+        5000,  //Far
+    );
+    // Move camera to look at center.
+    camera.position.set(-100, 100, -100);
+    camera.lookAt(0, 0, 0);
+    return camera;
+}
+// One camera per local player, the screen is split between them.
+const cameras = [create_camera()];
+
+function fit_cameras() {
+    // Narrower views when the screen is split, so nothing gets stretched.
+    for (let camera of cameras) {
+        camera.left = -3.2 * CAMERA_WIDTH / cameras.length;
+        camera.right = 3.2 * CAMERA_WIDTH / cameras.length;
+        camera.updateProjectionMatrix();
+    }
+}
 
 // Grid
 scene.add(new THREE.GridHelper(10, 10));
@@ -39,42 +53,92 @@ renderer.setSize(window.innerWidth, window.innerHeight);
 // Create the game structure
 let game_structure: GameContainer = GameContainer.create();
 
+// Rows go along z, the heights in a row along x.
 let map_heightmap = [
-    [0.0, 0.3, 0.3, 0.3, 0.0],
-    [0.0, 0.3, 0.3, 0.3, 0.0],
-    [0.0, 0.0, 0.0, 0.0, 0.0],
     [0.0, 0.0, 0.0, 0.0, 0.0],
+    [0.3, 0.3, 0.0, 0.0, 0.0],
+    [0.3, 0.3, 0.0, 0.0, 0.0],
+    [0.3, 0.3, 0.0, 0.0, 0.0],
     [0.0, 0.0, 0.0, 0.0, 0.0],
 ];
 
+// What every point of the map is made of, see SurfaceMaterial.
+// Asphalt (0) along two edges, grass (2) and a mud (4) pit.
+let map_materials = [
+    [0, 0, 0, 0, 0],
+    [2, 2, 2, 2, 0],
+    [2, 2, 2, 2, 0],
+    [2, 4, 2, 2, 0],
+    [2, 2, 2, 2, 0],
+];
+
 // Create the map with the heightmap
-load_map(map_heightmap);
+load_map(map_heightmap, map_materials);
 
 // Store keys
 let keys_pressed: GameKeysContainer = GameKeysContainer.new();
+// The second player drives with the arrow keys.
+let keys_pressed_2: GameKeysContainer = GameKeysContainer.new();
+const player_keys = [keys_pressed, keys_pressed_2];
 
 // Debug value for logging stuff on a key press.
 let debug_value: any;
 
-const renderLoop = () => {
-    // Run the game systems.
-    game_structure.run_systems(keys_pressed);
+// Threejs objects by entity id, null for the ones we don't render.
+const known_objects = new Map<number, THREE.Object3D | null>();
+// Ids of the objects that move.
+const dynamic_ids = new Set<number>();
+
+// Time of the last frame, the game runs at the same speed on every monitor.
+let last_frame_time: number | undefined = undefined;
+
+const renderLoop = (frame_time: number) => {
+    // Seconds since the last frame.
+    let elapsed = last_frame_time === undefined ? 0 : (frame_time - last_frame_time) / 1000;
+    last_frame_time = frame_time;
+
+    // Run the game systems, gamepads take priority over the keyboard.
+    let player_count = game_structure.player_count();
+    if (player_count > 1) {
+        let inputs = GameInputSet.new();
+        for (var slot = 0; slot < player_count; slot++) {
+            inputs.set(slot, read_gamepad(slot) ?? player_keys[slot]?.to_input() ?? GameInput.new());
+        }
+        game_structure.run_systems_multi(inputs, elapsed);
+    } else {
+        let gamepad_input = read_gamepad(0);
+        if (gamepad_input !== undefined) {
+            game_structure.run_systems_with_input(gamepad_input, elapsed);
+        } else {
+            game_structure.run_systems(keys_pressed, elapsed);
+        }
+    }
+
+    handle_events();
 
     // Compared threejs objects with Rust GameObjects
     let gameObjects: GameObjectContainer = game_structure.log_entities();
+
+    // Get every id and transform in one go instead of per object.
+    let ids = gameObjects.ids();
+    let transforms = gameObjects.transforms();
     
     // Create meshes if a gameObject without an id is found, update
     // the positions of the others.
-    for (var i = 0; i < gameObjects.len(); i++) {
-        let gameObject = catch_gameObject(gameObjects, i);
-        
-        let entID = gameObject.id();
-        let entName = gameObject.name();
-
-        // Check if an object exists with entID and entName
-        let object = scene.getObjectByName(entID + entName);
+    for (var i = 0; i < ids.length; i++) {
+        let entID = ids[i];
+
+        if (!known_objects.has(entID)) {
+            // Only new objects need the full GameObject.
+            let gameObject = catch_gameObject(gameObjects, i);
+            let entName = gameObject.name();
+
+            if (entName == "map00" || entName == "trig0") {
+                // The map has its own mesh, see load_map. Triggers are invisible.
+                known_objects.set(entID, null);
+                continue;
+            }
 
-        if (object === undefined && entName != "map00") {
             // Create that object!
             var newObject = create_object(entName);
             
@@ -82,140 +146,258 @@ const renderLoop = () => {
             newObject.name = entID + entName;
 
             // Set the position of that object.
-            update_object(newObject, gameObject);
+            update_object(newObject, transforms, i);
 
             scene.add(newObject);
+            known_objects.set(entID, newObject);
 
-            console.log(entID + entName + " built!")
-        } else {
-            
-            // Check if object is dynamic.
+            // Only dynamic objects move after being built.
             if (gameObject.physics_type() == PhysicsType.Dynamic) {
-                // Update that object!
-                update_object(object, gameObject);
-
-                // Check if this gameObject is our Player
-                if ( gameObject.name() == "car00" ) {
-                    // Update the camara's position to ours
-                    // but with it's offset.
-                    camara.position.setX(object.position.x + -CAMERA_DISTANCE);
-                    camara.position.setY(object.position.y +  CAMERA_DISTANCE);
-                    camara.position.setZ(object.position.z + -CAMERA_DISTANCE);
-                    
-                    camara.lookAt(object.position);
-                }
+                dynamic_ids.add(entID);
             }
+
+            console.log(entID + entName + " built!")
+        } else if (dynamic_ids.has(entID)) {
+            // Update that object!
+            let object = known_objects.get(entID);
+            update_object(object, transforms, i);
         }
     }
 
-    // Render the scene.
-    renderer.render(scene, camara);
+    update_race_hud();
+
+    // Render the scene, once per player side by side.
+    let width = window.innerWidth / cameras.length;
+    renderer.setScissorTest(cameras.length > 1);
+    for (var slot = 0; slot < cameras.length; slot++) {
+        let camera = cameras[slot];
+        let object = known_objects.get(game_structure.player_entity(slot));
+        if (object) {
+            // Update the camara's position to ours
+            // but with it's offset.
+            camera.position.setX(object.position.x + -CAMERA_DISTANCE);
+            camera.position.setY(object.position.y +  CAMERA_DISTANCE);
+            camera.position.setZ(object.position.z + -CAMERA_DISTANCE);
+
+            camera.lookAt(object.position);
+        }
+
+        renderer.setViewport(slot * width, 0, width, window.innerHeight);
+        renderer.setScissor(slot * width, 0, width, window.innerHeight);
+        renderer.render(scene, camera);
+    }
     // Console log the game objects.
     
 
     requestAnimationFrame(renderLoop);
 }
 
-function catch_gameObject(gameObjects: GameObjectContainer, idx: number): GameObject {
-    // Use the GameObjectContainer's .get() inside a catch.
-    try {
-        return gameObjects.get(idx);
-    } catch(err) {
-        throw new Error("Failed to get gameObject. " + err);
+// Contacts softer than this don't make a sound.
+const IMPACT_SOUND_IMPULSE = 500;
+
+function handle_events() {
+    // Go through the collisions since the last frame.
+    let events = game_structure.take_events();
+    for (var i = 0; i < events.len(); i++) {
+        let event = events.get(i);
+        if (event.kind() == GameEventKind.ContactStarted && event.impulse() > IMPACT_SOUND_IMPULSE) {
+            // TODO: play an impact sound and spawn sparks at event.point().
+            console.log("Impact between " + event.entity_a() + " and " + event.entity_b() + ": " + event.impulse());
+        }
     }
-
 }
 
-function update_object(object: THREE.Object3D, gameObject: GameObject) {
-    // Function to update an objects position and rotation.
-
-    let pos: Array<number> = gameObject.pos();
-    object.position.set(pos[0], pos[1], pos[2]);
-
-    let rot: Array<number> = gameObject.rot();
-    object.rotation.set(rot[0], rot[1], rot[2]);
+const race_hud = document.getElementById('race_hud');
 
+function format_time(time: number | undefined): string {
+    // Seconds as m:ss.mmm, or dashes for a time we don't have yet.
+    if (time === undefined) {
+        return "-:--.---";
+    }
+    let minutes = Math.floor(time / 60);
+    let seconds = (time % 60).toFixed(3).padStart(6, "0");
+    return minutes + ":" + seconds;
 }
 
-function load_map(heightmap: number[][]) {
-    // Delete previous map and add a new one.
-    let prevoius_map = scene.getObjectByName("map");
-    if (prevoius_map !== undefined) {
-        scene.remove(prevoius_map);
+function update_race_hud() {
+    let race = game_structure.race_state();
+    switch (race.phase()) {
+        case RacePhase.Idle:
+            race_hud.textContent = "Press Enter to start a race";
+            return;
+        case RacePhase.Countdown:
+            race_hud.textContent = Math.ceil(race.countdown()).toString();
+            return;
     }
 
-    // Create the entity and collider in the World.
-    game_structure.create_map(heightmap);
-
-    // Create the threejs object from points.
-
-    // Define the boundaries of the map in "game units".
-    let map_width  = 1000;
-    let map_height = 1000;
+    let lines = [
+        "Lap " + race.lap() + "/" + race.total_laps() + "   Pos " + race.position() + "/" + race.racers(),
+        "Checkpoint " + race.next_checkpoint() + "/" + race.checkpoint_count(),
+        "Lap  " + format_time(race.current_lap_time()),
+        "Last " + format_time(race.last_lap_time()),
+        "Best " + format_time(race.best_lap_time()),
+    ];
+    if (race.phase() == RacePhase.Finished) {
+        lines.unshift("Finished in " + format_time(race.finish_time()));
+    }
+    if (race.wrong_way()) {
+        lines.push("WRONG WAY");
+    } else if (race.missed_checkpoint()) {
+        lines.push("MISSED CHECKPOINT");
+    }
+    race_hud.textContent = lines.join("\n");
+}
 
-    // Values of the height map are multiplied by these game units.
-    let map_depth_factor = 100;
+// The game saved with "k", only kept until the page reloads.
+let quicksave: Uint8Array | undefined = undefined;
 
-    const geometry = new THREE.BufferGeometry();
-    let mesh;
+function quickload() {
+    if (quicksave === undefined) {
+        return;
+    }
+    try {
+        game_structure.load_state(quicksave);
+    } catch (err) {
+        console.log("Can't load the saved game: " + err);
+        return;
+    }
 
-    // Define how the vertices are connected.
-    const indices = [];
-    // Position and number of vertices.
-    const vertices = [];
-    const normals = [];
-    // Handles how textures are applied. Example of a plane: https://external-content.duckduckgo.com/iu/?u=https%3A%2F%2Faws1.discourse-cdn.com%2Fstandard17%2Fuploads%2Fthreejs%2Foptimized%2F2X%2F6%2F6f3b6cca517d1840321a3bd84bd4eccd01ffa60f_2_1000x1000.jpeg&f=1&nofb=1
-    const uv = []; 
+    // Entities may have changed, build every mesh again.
+    known_objects.forEach((object) => {
+        if (object !== null) {
+            scene.remove(object);
+        }
+    });
+    known_objects.clear();
+    dynamic_ids.clear();
+    // The saved game can have another map than the one on screen.
+    build_map_mesh();
+
+    cameras.length = 0;
+    for (let slot = 0; slot < game_structure.player_count(); slot++) {
+        cameras.push(create_camera());
+    }
+    fit_cameras();
+}
 
-    /* Here we define the values that we will use for the creation of the new geometry. */
-    const size = map_width;
-    const segments = heightmap.length - 1;
+function download_bytes(bytes: Uint8Array, file_name: string) {
+    let link = document.createElement("a");
+    link.href = URL.createObjectURL(new Blob([bytes], { type: "application/octet-stream" }));
+    link.download = file_name;
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
 
-    const halfSize = size / 2;
-    const segmentSize = size / segments;
+function download_ghost() {
+    // Save the best lap so it can be shared.
+    let bytes = game_structure.export_ghost();
+    if (bytes.length == 0) {
+        console.log("No ghost to export yet, finish a lap first.");
+        return;
+    }
+    download_bytes(bytes, "best_lap.ghost");
+}
 
-    // Generate vertices, normals and uv data for a simple grid geometry
-    for ( let i = 0; i <= segments; i ++ ) {
+// Drop a .ghost file on the page to race against it, or a grayscale
+// .png or .pgm to drive on it, at the size of the current map.
+document.addEventListener("dragover", (e) => e.preventDefault());
+document.addEventListener("drop", async (e) => {
+    e.preventDefault();
+    let file = e.dataTransfer?.files[0];
+    if (file === undefined) {
+        return;
+    }
+    let bytes = new Uint8Array(await file.arrayBuffer());
+    if (/\.(png|pgm)$/i.test(file.name)) {
+        try {
+            game_structure.create_map_from_image(bytes, game_structure.map_scale());
+            build_map_mesh();
+        } catch (err) {
+            console.log("Failed to load heightmap. " + err);
+        }
+        return;
+    }
+    try {
+        game_structure.import_ghost(bytes);
+    } catch (err) {
+        console.log("Failed to load ghost. " + err);
+    }
+});
+
+function read_gamepad(slot: number): GameInput | undefined {
+    // Every player uses their own gamepad with the standard mapping.
+    let gamepads = navigator.getGamepads().filter((pad) => pad !== null && pad.mapping == "standard");
+    let gamepad = gamepads[slot];
+    if (gamepad === undefined) {
+        return undefined;
+    }
 
-        const z = ( i * segmentSize ) - halfSize;
+    let input = GameInput.new();
+    input.set_throttle(gamepad.buttons[7].value);
+    input.set_brake(gamepad.buttons[6].value);
+    // Left stick, pushing left is positive.
+    input.set_steering(-gamepad.axes[0]);
+    input.set_handbrake(gamepad.buttons[0].pressed);
+    input.set_boost(gamepad.buttons[2].pressed);
+    input.set_reset(gamepad.buttons[3].pressed);
+    input.set_camera(gamepad.buttons[1].pressed);
+    input.set_gear_up(gamepad.buttons[5].pressed);
+    input.set_gear_down(gamepad.buttons[4].pressed);
+    return input;
+}
 
-        for ( let j = 0; j <= segments; j ++ ) {
+function catch_gameObject(gameObjects: GameObjectContainer, idx: number): GameObject {
+    // Use the GameObjectContainer's .get() inside a catch.
+    try {
+        return gameObjects.get(idx);
+    } catch(err) {
+        throw new Error("Failed to get gameObject. " + err);
+    }
 
-            const x = ( j * segmentSize ) - halfSize;
-            vertices.push( x, heightmap[i][j] * map_depth_factor, z );
-            normals.push( 0, 0, 1 );
+}
 
-            // Generate the UV indexes for the vertecies
-            let x_factor = i / segments;
-            let y_factor = j / segments;
+function update_object(object: THREE.Object3D, transforms: Float32Array, idx: number) {
+    // Function to update an objects position and rotation.
+    // Every object has 6 floats: position xyz and rotation xyz.
+    let offset = idx * 6;
 
-            uv.push( x_factor, y_factor );
-        }
-    }
-    // generate indices (data for element array buffer)
+    object.position.set(transforms[offset], transforms[offset + 1], transforms[offset + 2]);
+    object.rotation.set(transforms[offset + 3], transforms[offset + 4], transforms[offset + 5]);
 
-    for ( let i = 0; i < segments; i ++ ) {
+}
 
-        for ( let j = 0; j < segments; j ++ ) {
+function load_map(heightmap: number[][], materials?: number[][]) {
+    // Create the entity and collider in the World.
+    game_structure.create_map(heightmap, materials);
+    build_map_mesh();
+}
 
-            const a = i * ( segments + 1 ) + ( j + 1 );
-            const b = i * ( segments + 1 ) + j;
-            const c = ( i + 1 ) * ( segments + 1 ) + j;
-            const d = ( i + 1 ) * ( segments + 1 ) + ( j + 1 );
+function load_generated_map(params: object) {
+    // Let Rust generate the terrain from the world's seed.
+    game_structure.generate_map(JSON.stringify(params));
+    build_map_mesh();
+}
 
-            // generate two faces (triangles) per iteration
+function build_map_mesh() {
+    // Delete previous map and add a new one.
+    let prevoius_map = scene.getObjectByName("map");
+    if (prevoius_map !== undefined) {
+        scene.remove(prevoius_map);
+    }
 
-            indices.push( a, b, d ); // face one
-            indices.push( b, c, d ); // face two
+    // Rust builds the triangles from the map's collider, already
+    // scaled and placed in the world.
+    const map_mesh = game_structure.map_mesh();
 
-        }
-    }
+    const geometry = new THREE.BufferGeometry();
+    let mesh;
 
-    // Apply all of the generated variables to the geometry and index and attributes.
-    geometry.setIndex( indices );
-    geometry.setAttribute( 'position', new THREE.Float32BufferAttribute( vertices, 3 ) );
-    geometry.setAttribute( 'normal', new THREE.Float32BufferAttribute( normals, 3 ) );
-    geometry.setAttribute( 'uv', new THREE.Float32BufferAttribute(uv, 2));
+    // Apply the buffers to the geometry's index and attributes.
+    geometry.setIndex( new THREE.BufferAttribute( map_mesh.indices(), 1 ) );
+    geometry.setAttribute( 'position', new THREE.BufferAttribute( map_mesh.vertices(), 3 ) );
+    geometry.setAttribute( 'normal', new THREE.BufferAttribute( map_mesh.normals(), 3 ) );
+    geometry.setAttribute( 'uv', new THREE.BufferAttribute( map_mesh.uvs(), 2 ) );
+    map_mesh.free();
 
     // Create material with texture
     const loader = new THREE.TextureLoader();
@@ -232,7 +414,8 @@ function load_map(heightmap: number[][]) {
             imageRepeats,
         );
         
-        const material = new THREE.MeshBasicMaterial( {
+        // Lit, so the slopes show with the normals from Rust.
+        const material = new THREE.MeshLambertMaterial( {
             map: texture,
         } );
 
@@ -240,9 +423,6 @@ function load_map(heightmap: number[][]) {
         // the scene
         mesh = new THREE.Mesh( geometry, material );
         mesh.name = "map";
-        mesh.rotateY( 90 * Math.PI / 180);
-        
-        mesh.scale.multiply(new THREE.Vector3(-1, 1, 1)) ;
 
         // Return the map!
         scene.add( mesh );
@@ -264,6 +444,22 @@ function create_object(name: string) {
         return carObject;
 
         
+    }
+    if (name == "car01") {
+        // AI opponents.
+        return new THREE.Mesh(
+            new THREE.BoxGeometry(8, 2, 4),
+            new THREE.MeshLambertMaterial({ color: 0xd04040 })
+        );
+    }
+    if (name == "ghost") {
+        // Same size as the car, but see through.
+        let ghostObject = new THREE.Mesh(
+            new THREE.BoxGeometry(8, 2, 4),
+            new THREE.MeshBasicMaterial({ color: 0xffffff, transparent: true, opacity: 0.35 })
+        );
+
+        return ghostObject;
     }
     if (name == "floor") {
         let floorObject = new THREE.Mesh(
@@ -330,6 +526,67 @@ document.onkeydown = (e) => {
         case "d":
             keys_pressed.set(GameKeys.Right, true);
             break;
+        case "e":
+            keys_pressed.set(GameKeys.GearUp, true);
+            break;
+        case "q":
+            keys_pressed.set(GameKeys.GearDown, true);
+            break;
+        case " ":
+            keys_pressed.set(GameKeys.Handbrake, true);
+            break;
+        case "Shift":
+            keys_pressed.set(GameKeys.Boost, true);
+            break;
+        case "r":
+            keys_pressed.set(GameKeys.Reset, true);
+            break;
+        case "c":
+            keys_pressed.set(GameKeys.Camera, true);
+            break;
+        case "Enter":
+            game_structure.start_race(3);
+            break;
+        case "g":
+            download_ghost();
+            break;
+        case "2":
+            // Join as the second player, split screen.
+            if (game_structure.add_player() !== undefined) {
+                cameras.push(create_camera());
+                fit_cameras();
+            }
+            break;
+        case "ArrowUp":
+            keys_pressed_2.set(GameKeys.Acceleration, true);
+            break;
+        case "ArrowDown":
+            keys_pressed_2.set(GameKeys.Brakes, true);
+            break;
+        case "ArrowLeft":
+            keys_pressed_2.set(GameKeys.Left, true);
+            break;
+        case "ArrowRight":
+            keys_pressed_2.set(GameKeys.Right, true);
+            break;
+        case "p":
+            // Attach this to bug reports, see the headless runner's --replay.
+            download_bytes(game_structure.export_replay(), "session.replay");
+            break;
+        case "k":
+            quicksave = game_structure.save_state();
+            console.log("Game saved.");
+            break;
+        case "l":
+            quickload();
+            break;
+        case "m":
+            // Hills with a flat loop around the middle.
+            load_generated_map({
+                size: 65,
+                track: [[-200, -200], [200, -200], [200, 200], [-200, 200]],
+            });
+            break;
     }
 }
 document.onkeyup = (e) => {
@@ -349,6 +606,36 @@ document.onkeyup = (e) => {
         case "d":
             keys_pressed.set(GameKeys.Right, false);
             break;
+        case "e":
+            keys_pressed.set(GameKeys.GearUp, false);
+            break;
+        case "q":
+            keys_pressed.set(GameKeys.GearDown, false);
+            break;
+        case " ":
+            keys_pressed.set(GameKeys.Handbrake, false);
+            break;
+        case "Shift":
+            keys_pressed.set(GameKeys.Boost, false);
+            break;
+        case "r":
+            keys_pressed.set(GameKeys.Reset, false);
+            break;
+        case "c":
+            keys_pressed.set(GameKeys.Camera, false);
+            break;
+        case "ArrowUp":
+            keys_pressed_2.set(GameKeys.Acceleration, false);
+            break;
+        case "ArrowDown":
+            keys_pressed_2.set(GameKeys.Brakes, false);
+            break;
+        case "ArrowLeft":
+            keys_pressed_2.set(GameKeys.Left, false);
+            break;
+        case "ArrowRight":
+            keys_pressed_2.set(GameKeys.Right, false);
+            break;
         
         case "t":
             console.log(debug_value);